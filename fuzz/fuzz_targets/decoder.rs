@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use vcdiff_rs::{DecoderOptions, VCDiffDecoder};
+
+// the first byte selects how much of the input is used as source
+fuzz_target!(|data: &[u8]| {
+    if let Some((&split, data)) = data.split_first() {
+        let (source, delta) = data.split_at((split as usize).min(data.len()));
+        let mut target = Cursor::new(Vec::new());
+        let options = DecoderOptions::new()
+            .max_target_window_size(1 << 20)
+            .max_target_size(16 << 20)
+            .max_delta_section_size(1 << 20);
+        let mut decoder =
+            VCDiffDecoder::with_options(Cursor::new(source), &mut target, 128, options);
+        for chunk in delta.chunks(64) {
+            if decoder.decode(chunk).is_err() {
+                break;
+            }
+        }
+    }
+});
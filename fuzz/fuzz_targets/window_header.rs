@@ -0,0 +1,6 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = vcdiff_rs::fuzzing::window_header(data, vcdiff_rs::ChecksumDialect::Xdelta3);
+});
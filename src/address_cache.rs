@@ -1,6 +1,6 @@
+use crate::error::Error;
+use crate::varint::VarIntDecode;
 use nom::IResult;
-use std::io;
-use varint::VarIntDecode;
 
 static VCD_SELF: u8 = 0x00;
 static VCD_HERE: u8 = 0x01;
@@ -20,21 +20,15 @@ impl AddressCache {
         }
     }
 
-    pub fn reset(&mut self) {
-        for v in &mut self.near {
-            *v = 0;
+    pub fn update(&mut self, addr: u64) {
+        if !self.near.is_empty() {
+            self.near[self.next_slot] = addr;
+            self.next_slot = (self.next_slot + 1) % self.near.len();
         }
-        for v in &mut self.same {
-            *v = 0;
+        if !self.same.is_empty() {
+            let same_len = self.same.len() as u64;
+            self.same[(addr % same_len) as usize] = addr;
         }
-        self.next_slot = 0;
-    }
-
-    pub fn update(&mut self, addr: u64) {
-        self.near[self.next_slot] = addr;
-        self.next_slot = (self.next_slot + 1) % self.near.len();
-        let same_len = self.same.len() as u64;
-        self.same[(addr % same_len) as usize] = addr;
     }
 
     pub fn decode<'a>(
@@ -42,25 +36,19 @@ impl AddressCache {
         here: u64,
         mode: u8,
         input: &'a [u8],
-    ) -> Result<(&'a [u8], u64), io::Error> {
-        fn varint<'a>(input: &'a [u8]) -> Result<(&'a [u8], u64), io::Error> {
+    ) -> Result<(&'a [u8], u64), Error> {
+        fn varint(input: &[u8]) -> Result<(&[u8], u64), Error> {
             match u64::decode_varint(input) {
                 IResult::Done(r, sz) => Ok((r, sz)),
-                _ => Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "unable to get instruction address",
-                )),
+                _ => Err(Error::InvalidCopyAddress),
             }
         }
 
-        fn one<'a>(input: &'a [u8]) -> Result<(&'a [u8], u64), io::Error> {
-            if input.len() > 0 {
+        fn one(input: &[u8]) -> Result<(&[u8], u64), Error> {
+            if !input.is_empty() {
                 Ok((&input[1..], input[0] as u64))
             } else {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    "unable to get instruction address",
-                ))
+                Err(Error::InvalidCopyAddress)
             }
         }
 
@@ -69,22 +57,45 @@ impl AddressCache {
             res = varint(input)?;
         } else if mode == VCD_HERE {
             res = varint(input)?;
-            res.1 = here - res.1;
+            res.1 = here.checked_sub(res.1).ok_or(Error::InvalidCopyAddress)?;
         } else if mode >= 2 && (mode as usize) - 2 < self.near.len() {
             res = varint(input)?;
-            res.1 = self.near[(mode as usize) - 2] + res.1;
-        } else {
+            res.1 = res
+                .1
+                .checked_add(self.near[(mode as usize) - 2])
+                .ok_or(Error::InvalidCopyAddress)?;
+        } else if (mode as usize) - 2 - self.near.len() < self.same.len() / 256 {
             res = one(input)?;
             let m = (mode as usize) - 2 - self.near.len();
             res.1 = self.same[m * 256 + res.1 as usize];
+        } else {
+            return Err(Error::InvalidCopyAddress);
         }
 
+        if res.1 >= here {
+            return Err(Error::InvalidCopyAddress);
+        }
         self.update(res.1);
         Ok(res)
     }
 
-    #[cfg(encoder)]
+    #[cfg(feature = "encoder")]
     pub fn encode(&mut self, addr: u64, here: u64) -> (u64, u8) {
+        let best = self.find(addr, here);
+        self.update(addr);
+        best
+    }
+
+    /// returns the encoded address and the mode of `addr`, without updating the caches
+    #[cfg(feature = "encoder")]
+    pub fn find(&self, addr: u64, here: u64) -> (u64, u8) {
+        self.find_after(addr, here, &[])
+    }
+
+    /// returns the encoded address and the mode of `addr` once the addresses of `copies`
+    /// are encoded, in order, without updating the caches
+    #[cfg(feature = "encoder")]
+    pub fn find_after(&self, addr: u64, here: u64, copies: &[u64]) -> (u64, u8) {
         /* Attempt to find the address mode that yields the
          * smallest integer value for "d", the encoded address
          * value, thereby minimizing the encoded size of the
@@ -95,18 +106,63 @@ impl AddressCache {
             best = (here - addr, VCD_HERE);
         }
 
+        let near_len = self.near.len();
         for (i, &near) in self.near.iter().enumerate() {
-            if addr > near && addr - near < best.0 {
+            // the last of `copies` written to the slot, which are filled in turn
+            let first = (i + near_len - self.next_slot) % near_len;
+            let near = match copies.len().checked_sub(first + 1) {
+                Some(after) => copies[first + after / near_len * near_len],
+                None => near,
+            };
+            if addr >= near && addr - near < best.0 {
                 best = (addr - near, (i as u8) + 2);
             }
         }
 
-        let idx = (addr % (self.same.len() as u64)) as usize;
-        if self.same[idx] == addr {
-            best = ((idx % 256) as u64, (self.near.len() + 2 + idx / 256) as u8)
+        if !self.same.is_empty() {
+            let same_len = self.same.len() as u64;
+            let idx = (addr % same_len) as usize;
+            let same = copies
+                .iter()
+                .rev()
+                .find(|&&copy| copy % same_len == idx as u64)
+                .unwrap_or(&self.same[idx]);
+            if *same == addr {
+                best = ((idx % 256) as u64, (near_len + 2 + idx / 256) as u8)
+            }
         }
-
-        self.update(best.0);
         best
     }
+
+    /// same modes encode their address as a single byte instead of a varint
+    #[cfg(feature = "encoder")]
+    pub fn is_same_mode(&self, mode: u8) -> bool {
+        (mode as usize) >= 2 + self.near.len()
+    }
+}
+
+#[cfg(all(test, feature = "encoder"))]
+mod tests {
+    use super::AddressCache;
+
+    #[test]
+    fn find_after() {
+        let mut cache = AddressCache::new(3, 1);
+        for &addr in &[10, 300, 1000, 5000, 20] {
+            cache.update(addr);
+        }
+        let copies = [700, 4000, 1000, 257, 6000];
+        for prefix in 0..=copies.len() {
+            let mut expected = AddressCache::new(3, 1);
+            for &addr in [10, 300, 1000, 5000, 20].iter().chain(&copies[..prefix]) {
+                expected.update(addr);
+            }
+            for addr in [0, 20, 257, 300, 701, 1000, 1001, 4002, 5000, 6000, 6010] {
+                assert_eq!(
+                    cache.find_after(addr, 7000, &copies[..prefix]),
+                    expected.find(addr, 7000)
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,32 @@
+/// largest prime smaller than 65536
+static MOD_ADLER: u32 = 65521;
+
+/// largest n such that 255n(n+1)/2 + (n+1)(MOD_ADLER-1) <= 2^32-1
+static NMAX: usize = 5552;
+
+/// Adler-32 checksum of `data`, as stored in VCD_ADLER32 windows
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for chunk in data.chunks(NMAX) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::adler32;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(&[0xFF; 100_000]), 0x149A_302C);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::decoder::{DecoderOptions, DecoderState};
+use crate::decoder_core::{DecoderCore, RawWindow, Step};
+use crate::error::Error;
+use crate::instructions::Op;
+use crate::secondary::SecondaryCompressor;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// `VCDiffDecoder` for tokio sources and targets
+pub struct AsyncVCDiffDecoder<ORIGINAL, TARGET>
+where
+    ORIGINAL: AsyncRead + AsyncSeek + Unpin,
+    TARGET: AsyncWrite + Unpin,
+{
+    original: ORIGINAL,
+    target: TARGET,
+    core: DecoderCore,
+}
+
+impl<ORIGINAL, TARGET> AsyncVCDiffDecoder<ORIGINAL, TARGET>
+where
+    ORIGINAL: AsyncRead + AsyncSeek + Unpin,
+    TARGET: AsyncWrite + Unpin,
+{
+    pub fn new(
+        original: ORIGINAL,
+        target: TARGET,
+        buffer_size: usize,
+    ) -> AsyncVCDiffDecoder<ORIGINAL, TARGET> {
+        AsyncVCDiffDecoder::with_options(original, target, buffer_size, DecoderOptions::default())
+    }
+
+    pub fn with_options(
+        original: ORIGINAL,
+        target: TARGET,
+        buffer_size: usize,
+        options: DecoderOptions,
+    ) -> AsyncVCDiffDecoder<ORIGINAL, TARGET> {
+        AsyncVCDiffDecoder {
+            original,
+            target,
+            core: DecoderCore::new(buffer_size, options),
+        }
+    }
+
+    /// registers a secondary compressor, replacing any known compressor with the same id
+    pub fn add_secondary_compressor(&mut self, compressor: Box<dyn SecondaryCompressor>) {
+        self.core.add_secondary_compressor(compressor);
+    }
+
+    async fn decode_window(&mut self, window: RawWindow<'_>) -> Result<(), Error> {
+        let sections = self.core.sections(window)?;
+        let (instructions, mut target_window) = self.core.window(&sections)?;
+        for op in instructions {
+            match op? {
+                Op::Add(bytes) => target_window.add(bytes),
+                Op::Run(byte, len) => target_window.run(byte, len),
+                Op::Copy { addr, len, from } => {
+                    if let Some((pos, buf)) = target_window.copy_from_segment(addr, len, from) {
+                        self.original.seek(io::SeekFrom::Start(pos)).await?;
+                        self.original.read_exact(buf).await?;
+                    }
+                    target_window.copy_from_window(addr, len, from);
+                }
+            }
+        }
+        let target_data = target_window.into_data();
+        self.core.finish_window(&target_data)?;
+        self.target.write_all(&target_data).await?;
+        Ok(())
+    }
+
+    /// application defined header of the delta (VCD_APPHEADER), available once the header is decoded
+    pub fn app_header(&self) -> Option<&[u8]> {
+        self.core.app_header()
+    }
+
+    pub fn get_mut(&mut self) -> (&mut ORIGINAL, &mut TARGET) {
+        (&mut self.original, &mut self.target)
+    }
+
+    pub fn into_inner(self) -> (ORIGINAL, TARGET) {
+        (self.original, self.target)
+    }
+
+    pub async fn decode(&mut self, input: &[u8]) -> Result<DecoderState, Error> {
+        let mut buffer = self.core.take_buffer();
+        let available = if buffer.is_empty() {
+            input
+        } else {
+            buffer.extend_from_slice(input);
+            &buffer[..]
+        };
+        let mut remaining = available;
+        while let Some((r, step)) = self.core.step(remaining)? {
+            if let Step::Window(window) = step {
+                self.decode_window(window).await?;
+            }
+            remaining = r;
+        }
+        let unconsumed = remaining.len();
+        self.core.keep_unconsumed(buffer, input, unconsumed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncVCDiffDecoder;
+    use crate::test_util::read_file;
+    use crate::DecoderState;
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn text_1() {
+        let src = read_file("tst/text-1/src.txt");
+        let patch = read_file("tst/text-1/l.patch");
+        let target = read_file("tst/text-1/target.txt");
+
+        let mut decoder = AsyncVCDiffDecoder::new(Cursor::new(src), Vec::new(), 128);
+        let mut state = DecoderState::WantMoreInput;
+        for chunk in patch.chunks(128) {
+            state = decoder.decode(chunk).await.unwrap();
+        }
+        assert_eq!(state, DecoderState::WantMoreInputOrDone);
+        assert!(decoder.app_header().unwrap().ends_with(b"\\LICENSE/"));
+        assert_eq!(decoder.into_inner().1, target);
+    }
+
+    /// decoding futures can be spawned on a multi-threaded runtime
+    #[allow(dead_code)]
+    fn decode_is_send(decoder: &mut AsyncVCDiffDecoder<Cursor<Vec<u8>>, Vec<u8>>) {
+        fn is_send<T: Send>(_: T) {}
+        is_send(decoder.decode(b""));
+    }
+}
@@ -0,0 +1,241 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::process;
+use vcdiff_rs::{
+    windows, CodeTable, CopyFrom, DecoderState, Instruction, InstructionType, Op, VCDiffDecoder,
+    VCDiffEncoder, WindowHeader, Windows,
+};
+
+static USAGE: &str = "usage:
+    vcdiff encode <source> <target> <patch>
+    vcdiff decode <source> <patch> <output>
+    vcdiff info <patch>
+    vcdiff dump <patch>";
+
+/// size of the rolling hash window used to find matches
+static DIFF_WINDOW_SIZE: usize = 16;
+
+#[derive(Debug)]
+struct CliError(String);
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl<E: std::error::Error> From<E> for CliError {
+    fn from(err: E) -> CliError {
+        CliError(err.to_string())
+    }
+}
+
+fn open(path: &str) -> Result<File, CliError> {
+    File::open(path).map_err(|err| CliError(format!("{}: {}", path, err)))
+}
+
+fn create(path: &str) -> Result<File, CliError> {
+    File::create(path).map_err(|err| CliError(format!("{}: {}", path, err)))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, CliError> {
+    let mut data = Vec::new();
+    open(path)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn encode(source: &str, target: &str, patch: &str) -> Result<(), CliError> {
+    let mut encoder = VCDiffEncoder::new(open(source)?, open(target)?, DIFF_WINDOW_SIZE)?;
+    let mut out = BufWriter::new(create(patch)?);
+    encoder.encode(&mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn decode(source: &str, patch: &str, output: &str) -> Result<(), CliError> {
+    let mut patch = open(patch)?;
+    let mut out = BufWriter::new(create(output)?);
+    let mut state = DecoderState::WantMoreInput;
+    {
+        let mut decoder = VCDiffDecoder::new(open(source)?, &mut out, 0);
+        let mut chunk = vec![0u8; 64 * 1024];
+        loop {
+            let read = patch.read(&mut chunk)?;
+            if read == 0 {
+                break;
+            }
+            state = decoder.decode(&chunk[..read])?;
+        }
+    }
+    if state != DecoderState::WantMoreInputOrDone {
+        return Err(CliError("unexpected end of patch".to_string()));
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn win_indicator(header: &WindowHeader) -> String {
+    let mut flags = Vec::new();
+    for &(flag, name) in &[
+        (0x01, "VCD_SOURCE"),
+        (0x02, "VCD_TARGET"),
+        (0x04, "VCD_ADLER32"),
+    ] {
+        if header.win_indicator & flag != 0 {
+            flags.push(name);
+        }
+    }
+    if flags.is_empty() {
+        "none".to_string()
+    } else {
+        flags.join(" ")
+    }
+}
+
+fn print_window_header(number: usize, header: &WindowHeader) {
+    println!("window number:            {}", number);
+    println!("window indicator:         {}", win_indicator(header));
+    if let Some((pos, sz)) = header.source_segment {
+        println!("source segment offset:    {}", pos);
+        println!("source segment length:    {}", sz);
+    }
+    if let Some(adler32) = header.adler32 {
+        println!("adler32 checksum:         {:08X}", adler32);
+    }
+    println!("delta encoding length:    {}", header.delta_encoding_size);
+    println!("target window length:     {}", header.target_window_size);
+    println!("delta indicator:          {:#04x}", header.delta_indicator);
+    println!("data section length:      {}", header.adds_runs_size);
+    println!("instructions length:      {}", header.instructions_size);
+    println!("addresses length:         {}", header.copy_addresses_size);
+}
+
+fn instruction(inst: &Instruction) -> String {
+    match inst.typ {
+        InstructionType::Add => format!("ADD {}", inst.size),
+        InstructionType::Run => format!("RUN {}", inst.size),
+        InstructionType::Copy => format!("CPY {} mode {}", inst.size, inst.mode),
+    }
+}
+
+/// prints the cache sizes of the code table and its entries that differ from the default
+/// code table
+fn print_code_table(windows: &Windows) {
+    let code_table = windows.code_table();
+    if !windows.has_custom_code_table() {
+        println!("code table:               default");
+        return;
+    }
+    println!("code table:               custom");
+    println!("near cache size:          {}", code_table.near_cache_size());
+    println!("same cache size:          {}", code_table.same_cache_size());
+    let default = CodeTable::default();
+    for opcode in 0..=255 {
+        let entry = code_table.entry(opcode);
+        if entry == default.entry(opcode) {
+            continue;
+        }
+        let entry = match entry {
+            None => "NOOP".to_string(),
+            Some((first, None)) => instruction(&first),
+            Some((first, Some(second))) => {
+                format!("{} + {}", instruction(&first), instruction(&second))
+            }
+        };
+        println!("  opcode {:<3}               {}", opcode, entry);
+    }
+}
+
+fn print_header(windows: &Windows) {
+    let format = match windows.version() {
+        0x53 => "open-vcdiff",
+        _ => "RFC 3284",
+    };
+    println!("format:                   {}", format);
+    match windows.secondary_compressor_id() {
+        Some(id) => println!("secondary compressor:     {}", id),
+        None => println!("secondary compressor:     none"),
+    }
+    print_code_table(windows);
+    match windows.app_header() {
+        Some(app_header) => println!(
+            "application header:       {}",
+            String::from_utf8_lossy(app_header)
+        ),
+        None => println!("application header:       none"),
+    }
+}
+
+/// prints the header and the windows of `patch`, with their instructions if `dump` is set
+fn inspect(patch: &str, dump: bool) -> Result<(), CliError> {
+    let patch = read(patch)?;
+    let mut windows = windows(&patch);
+    let mut target_offset = 0u64;
+    let mut number = 0;
+    while let Some(window) = windows.next() {
+        let window = window?;
+        if number == 0 {
+            print_header(&windows);
+        }
+        println!();
+        let header = window.header();
+        print_window_header(number, header);
+        if dump {
+            println!("  offset    op   size     address");
+            let mut offset = target_offset;
+            for op in window.instructions() {
+                match op? {
+                    Op::Add(bytes) => {
+                        println!("  {:08}  ADD  {}", offset, bytes.len());
+                        offset += bytes.len() as u64;
+                    }
+                    Op::Run(byte, len) => {
+                        println!("  {:08}  RUN  {:<8} {:#04x}", offset, len, byte);
+                        offset += len as u64;
+                    }
+                    Op::Copy { addr, len, from } => {
+                        let from = match from {
+                            CopyFrom::Source => 'S',
+                            CopyFrom::Target => 'T',
+                        };
+                        println!("  {:08}  CPY  {:<8} {}@{}", offset, len, from, addr);
+                        offset += len as u64;
+                    }
+                }
+            }
+        }
+        target_offset += u64::from(header.target_window_size);
+        number += 1;
+    }
+    if number == 0 {
+        print_header(&windows);
+    }
+    println!();
+    println!("windows:                  {}", number);
+    println!("target length:            {}", target_offset);
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), CliError> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    match args[..] {
+        ["encode", source, target, patch] => encode(source, target, patch),
+        ["decode", source, patch, output] => decode(source, patch, output),
+        ["info", patch] => inspect(patch, false),
+        ["dump", patch] => inspect(patch, true),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(err) = run(&args) {
+        eprintln!("vcdiff: {}", err);
+        process::exit(1);
+    }
+}
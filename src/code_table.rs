@@ -1,144 +1,224 @@
+#[cfg(feature = "encoder")]
+use crate::address_cache::AddressCache;
+use crate::error::Error;
 use nom::{ErrorKind, IResult, Needed};
+#[cfg(feature = "encoder")]
+use std::collections::HashMap;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum InstructionType {
     Add,
     Run,
     Copy,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// instruction of a code table entry, a size of 0 means the size follows the opcode
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Instruction {
     pub typ: InstructionType,
     pub size: u8,
     pub mode: u8,
 }
 
+impl Instruction {
+    pub fn add(size: u8) -> Instruction {
+        Instruction {
+            typ: InstructionType::Add,
+            size,
+            mode: 0,
+        }
+    }
+
+    pub fn run(size: u8) -> Instruction {
+        Instruction {
+            typ: InstructionType::Run,
+            size,
+            mode: 0,
+        }
+    }
+
+    /// COPY with the address `mode`: 0 for VCD_SELF, 1 for VCD_HERE, then the near modes
+    /// and the same modes
+    pub fn copy(size: u8, mode: u8) -> Instruction {
+        Instruction {
+            typ: InstructionType::Copy,
+            size,
+            mode,
+        }
+    }
+}
+
+/// nom custom error of malformed code tables
+pub const INVALID_CODE_TABLE: u32 = 0x100;
+
+/// size of the byte layout of a code table, see `CodeTable::encode`
+pub const CODE_TABLE_SIZE: usize = 256 * 3 * 2;
+
+/// instructions of an opcode, `None` for the opcodes made of two NOOPs
+type Entry = Option<(Instruction, Option<Instruction>)>;
+
+/// maps the opcodes of a delta to their instructions
+#[derive(Debug, Clone, PartialEq)]
 pub struct CodeTable {
-    pub entries: [(Instruction, Option<Instruction>); 256],
+    pub(crate) entries: [Entry; 256],
+    /// sizes of the address caches, which give the COPY modes available to the entries
+    pub(crate) near_cache_size: u8,
+    pub(crate) same_cache_size: u8,
 }
 
 impl CodeTable {
-    pub fn decode(bytes: &[u8]) -> IResult<&[u8], CodeTable> {
-        if bytes.len() != 256 * 3 * 2 {
-            return IResult::Incomplete(Needed::Size(256 * 3 * 2));
-        }
-
-        let res = (|| -> Result<CodeTable, u32> {
-            let mut vec = [(
-                Instruction {
-                    typ: InstructionType::Add,
-                    size: 0,
-                    mode: 0,
-                },
-                None,
-            ); 256];
-            for i in 0..256 {
-                vec[i].0 = Instruction {
-                    typ: match bytes[i] {
-                        1 => Ok(InstructionType::Add),
-                        2 => Ok(InstructionType::Run),
-                        3 => Ok(InstructionType::Copy),
-                        _ => Err(0u32),
-                    }?,
-                    size: bytes[i + 512],
-                    mode: bytes[i + 1024],
-                };
-                vec[i].1 = match bytes[i + 256] {
-                    0 => Ok(None),
-                    1 => Ok(Some(InstructionType::Add)),
-                    2 => Ok(Some(InstructionType::Run)),
-                    3 => Ok(Some(InstructionType::Copy)),
-                    _ => Err(1u32),
-                }?
-                .map(|typ| Instruction {
-                    typ: typ,
-                    size: bytes[i + 256 + 512],
-                    mode: bytes[i + 256 + 1024],
-                });
-            }
-            Ok(CodeTable { entries: vec })
-        })();
-
-        match res {
-            Ok(code_table) => IResult::Done(&bytes[256 * 3 * 2..], code_table),
-            Err(n) => IResult::Error(ErrorKind::Custom(n)),
+    /// starts a custom code table, with the default cache sizes
+    pub fn builder() -> CodeTableBuilder {
+        CodeTableBuilder {
+            entries: Vec::new(),
+            near_cache_size: 4,
+            same_cache_size: 3,
         }
     }
 
-    #[cfg(encoder)]
-    pub fn encode(&self) -> [u8; 256 * 3 * 2] {
-        let mut ret = [0u8; 256 * 3 * 2];
+    /// instructions of `opcode`
+    pub fn entry(&self, opcode: u8) -> Option<(Instruction, Option<Instruction>)> {
+        self.entries[opcode as usize]
+    }
 
-        for i in 0..256 {
-            let e = self.entries[i];
-            let inst0 = encode_inst(e.0);
-            let inst1 = e.1.map_or((0, 0, 0), |inst| encode_inst(inst));
-            ret[i] = inst0.0;
-            ret[i + 256] = inst1.0;
-            ret[i + 512] = inst0.1;
-            ret[i + 768] = inst1.1;
-            ret[i + 512] = inst0.2;
-            ret[i + 768] = inst1.2;
-        }
+    pub fn near_cache_size(&self) -> u8 {
+        self.near_cache_size
+    }
 
-        fn encode_inst(inst: Instruction) -> (u8, u8, u8) {
-            (
-                match inst.typ {
-                    InstructionType::Add => 1,
-                    InstructionType::Run => 2,
-                    InstructionType::Copy => 3,
-                },
-                inst.size,
-                inst.mode,
-            )
+    pub fn same_cache_size(&self) -> u8 {
+        self.same_cache_size
+    }
+
+    /// parses the byte layout of the entries of a code table (see `encode`), whose
+    /// address caches have the given sizes.
+    ///
+    /// the table is checked like `CodeTableBuilder::build` does.
+    pub fn decode(
+        bytes: &[u8],
+        near_cache_size: u8,
+        same_cache_size: u8,
+    ) -> Result<CodeTable, Error> {
+        let entries = match entries(bytes) {
+            IResult::Done(&[], entries) => entries,
+            _ => Err(Error::InvalidCodeTable)?,
+        };
+        check_entries(&entries, near_cache_size, same_cache_size)?;
+        Ok(CodeTable {
+            entries,
+            near_cache_size,
+            same_cache_size,
+        })
+    }
+
+    /// byte layout of the entries (RFC 3284 section 7): the types of the first and second
+    /// instructions, then their sizes, then their modes, 256 bytes each
+    pub fn encode(&self) -> [u8; CODE_TABLE_SIZE] {
+        let mut ret = [0u8; CODE_TABLE_SIZE];
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let (first, second) = match *entry {
+                Some((first, second)) => (Some(first), second),
+                None => (None, None),
+            };
+            for (offset, inst) in [(i, first), (i + 256, second)] {
+                if let Some(inst) = inst {
+                    ret[offset] = match inst.typ {
+                        InstructionType::Add => 1,
+                        InstructionType::Run => 2,
+                        InstructionType::Copy => 3,
+                    };
+                    ret[offset + 512] = inst.size;
+                    ret[offset + 1024] = inst.mode;
+                }
+            }
         }
 
         ret
     }
 }
 
+/// parses the byte layout of the entries of a code table
+pub(crate) fn entries(bytes: &[u8]) -> IResult<&[u8], [Entry; 256]> {
+    if bytes.len() < CODE_TABLE_SIZE {
+        return IResult::Incomplete(Needed::Size(CODE_TABLE_SIZE));
+    }
+
+    let inst = |i: usize| -> Result<Option<Instruction>, ()> {
+        let typ = match bytes[i] {
+            0 => return Ok(None),
+            1 => InstructionType::Add,
+            2 => InstructionType::Run,
+            3 => InstructionType::Copy,
+            _ => return Err(()),
+        };
+        Ok(Some(Instruction {
+            typ,
+            size: bytes[i + 512],
+            mode: bytes[i + 1024],
+        }))
+    };
+    let res = (|| -> Result<[Entry; 256], ()> {
+        let mut entries = [None; 256];
+        for (i, entry) in entries.iter_mut().enumerate() {
+            *entry = match (inst(i)?, inst(i + 256)?) {
+                (Some(first), second) => Some((first, second)),
+                (None, Some(second)) => Some((second, None)),
+                (None, None) => None,
+            };
+        }
+        Ok(entries)
+    })();
+
+    match res {
+        Ok(entries) => IResult::Done(&bytes[CODE_TABLE_SIZE..], entries),
+        Err(()) => IResult::Error(ErrorKind::Custom(INVALID_CODE_TABLE)),
+    }
+}
+
+/// checks a table can encode every instruction: it fits in 256 opcodes, its COPY modes
+/// exist and it has opcodes with an explicit size for ADD, RUN and COPY in every mode.
+fn check_entries(entries: &[Entry], near_cache_size: u8, same_cache_size: u8) -> Result<(), Error> {
+    let modes = 2 + near_cache_size as usize + same_cache_size as usize;
+    if entries.len() > 256 || modes > 256 {
+        Err(Error::InvalidCodeTable)?;
+    }
+    let mut explicit_copies = vec![false; modes];
+    let (mut explicit_add, mut explicit_run) = (false, false);
+    for &(first, second) in entries.iter().flatten() {
+        for inst in Some(first).iter().chain(second.iter()) {
+            if inst.typ == InstructionType::Copy && inst.mode as usize >= modes {
+                Err(Error::InvalidCodeTable)?;
+            }
+        }
+        if second.is_none() && first.size == 0 {
+            match first.typ {
+                InstructionType::Add => explicit_add = true,
+                InstructionType::Run => explicit_run = true,
+                InstructionType::Copy => explicit_copies[first.mode as usize] = true,
+            }
+        }
+    }
+    if !explicit_add || !explicit_run || explicit_copies.contains(&false) {
+        Err(Error::InvalidCodeTable)?;
+    }
+    Ok(())
+}
+
 impl Default for CodeTable {
+    /// the code table of RFC 3284 section 5.6
     fn default() -> CodeTable {
-        let mut vec = [(
-            Instruction {
-                typ: InstructionType::Add,
-                size: 0,
-                mode: 0,
-            },
-            None,
-        ); 256];
-        let mut idx = 0;
-        vec[idx].0 = Instruction {
-            typ: InstructionType::Run,
-            size: 0,
-            mode: 0,
-        };
-        idx += 1;
+        let mut builder = CodeTable::builder().single(Instruction::run(0));
+
+        // Entries 1-18
         for size in 0..18 {
-            vec[idx].0 = Instruction {
-                typ: InstructionType::Add,
-                size: size,
-                mode: 0,
-            };
-            idx += 1;
+            builder = builder.single(Instruction::add(size));
         }
 
         // Entries 19-162
         for mode in 0..9 {
-            vec[idx].0 = Instruction {
-                typ: InstructionType::Copy,
-                size: 0,
-                mode: mode,
-            };
-            idx += 1;
+            builder = builder.single(Instruction::copy(0, mode));
             for size in 4..19 {
-                vec[idx].0 = Instruction {
-                    typ: InstructionType::Copy,
-                    size: size,
-                    mode: mode,
-                };
-                idx += 1;
+                builder = builder.single(Instruction::copy(size, mode));
             }
         }
 
@@ -146,19 +226,10 @@ impl Default for CodeTable {
         for mode in 0..6 {
             for add_size in 1..5 {
                 for copy_size in 4..7 {
-                    vec[idx] = (
-                        Instruction {
-                            typ: InstructionType::Add,
-                            size: add_size,
-                            mode: 0,
-                        },
-                        Some(Instruction {
-                            typ: InstructionType::Copy,
-                            size: copy_size,
-                            mode: mode,
-                        }),
+                    builder = builder.double(
+                        Instruction::add(add_size),
+                        Instruction::copy(copy_size, mode),
                     );
-                    idx += 1;
                 }
             }
         }
@@ -166,39 +237,245 @@ impl Default for CodeTable {
         // Entries 235-246
         for mode in 6..9 {
             for add_size in 1..5 {
-                vec[idx] = (
-                    Instruction {
-                        typ: InstructionType::Add,
-                        size: add_size,
-                        mode: 0,
-                    },
-                    Some(Instruction {
-                        typ: InstructionType::Copy,
-                        size: 4,
-                        mode: mode,
-                    }),
-                );
-                idx += 1;
+                builder = builder.double(Instruction::add(add_size), Instruction::copy(4, mode));
             }
         }
 
         // Entries 247-255
         for mode in 0..9 {
-            vec[idx] = (
-                Instruction {
-                    typ: InstructionType::Copy,
-                    size: 4,
-                    mode: mode,
-                },
-                Some(Instruction {
-                    typ: InstructionType::Add,
-                    size: 1,
-                    mode: 0,
-                }),
-            );
-            idx += 1;
-        }
-
-        CodeTable { entries: vec }
+            builder = builder.double(Instruction::copy(4, mode), Instruction::add(1));
+        }
+
+        builder.build().expect("valid default code table")
+    }
+}
+
+/// builds a custom code table, see `CodeTable::builder`.
+///
+/// opcodes are given to the entries in the order they are added, the opcodes left
+/// over are NOOPs.
+#[derive(Debug, Clone)]
+pub struct CodeTableBuilder {
+    entries: Vec<Entry>,
+    near_cache_size: u8,
+    same_cache_size: u8,
+}
+
+impl CodeTableBuilder {
+    /// size of the cache of the recently copied addresses (4 by default)
+    pub fn near_cache_size(mut self, size: u8) -> CodeTableBuilder {
+        self.near_cache_size = size;
+        self
+    }
+
+    /// number of 256 addresses blocks of the cache indexed by address (3 by default)
+    pub fn same_cache_size(mut self, size: u8) -> CodeTableBuilder {
+        self.same_cache_size = size;
+        self
+    }
+
+    /// adds an opcode for a single instruction
+    pub fn single(mut self, inst: Instruction) -> CodeTableBuilder {
+        self.entries.push(Some((inst, None)));
+        self
+    }
+
+    /// adds an opcode for two instructions in a row
+    pub fn double(mut self, first: Instruction, second: Instruction) -> CodeTableBuilder {
+        self.entries.push(Some((first, Some(second))));
+        self
+    }
+
+    /// checks the table can encode every instruction: it fits in 256 opcodes, its COPY modes
+    /// exist and it has opcodes with an explicit size for ADD, RUN and COPY in every mode.
+    pub fn build(self) -> Result<CodeTable, Error> {
+        check_entries(&self.entries, self.near_cache_size, self.same_cache_size)?;
+
+        let mut entries = [None; 256];
+        entries[..self.entries.len()].copy_from_slice(&self.entries);
+        Ok(CodeTable {
+            entries,
+            near_cache_size: self.near_cache_size,
+            same_cache_size: self.same_cache_size,
+        })
+    }
+}
+
+#[cfg(feature = "encoder")]
+type InstructionKey = (InstructionType, u8, u8);
+
+/// reverse lookup of a code table, from instructions to opcodes.
+///
+/// sizes of 0 in the table mean the size is written explicitly after the opcode.
+#[cfg(feature = "encoder")]
+pub struct OpcodeMap {
+    near_cache_size: usize,
+    same_cache_size: usize,
+    /// opcode of each single instruction, by `OpcodeMap::index`
+    singles: Vec<Option<u8>>,
+    /// whether an instruction starts a pair, by `OpcodeMap::index`
+    double_firsts: Vec<bool>,
+    doubles: HashMap<(InstructionKey, InstructionKey), u8>,
+}
+
+#[cfg(feature = "encoder")]
+impl OpcodeMap {
+    pub fn new(code_table: &CodeTable) -> OpcodeMap {
+        let mut singles = vec![None; 3 << 16];
+        let mut double_firsts = vec![false; 3 << 16];
+        let mut doubles = HashMap::new();
+        for (opcode, entry) in code_table.entries.iter().enumerate() {
+            let (first, second) = match *entry {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let first = (first.typ, first.size, first.mode);
+            match second {
+                None => {
+                    singles[OpcodeMap::index(first)].get_or_insert(opcode as u8);
+                }
+                Some(second) => {
+                    double_firsts[OpcodeMap::index(first)] = true;
+                    doubles
+                        .entry((first, (second.typ, second.size, second.mode)))
+                        .or_insert(opcode as u8);
+                }
+            }
+        }
+        OpcodeMap {
+            near_cache_size: code_table.near_cache_size as usize,
+            same_cache_size: code_table.same_cache_size as usize,
+            singles,
+            double_firsts,
+            doubles,
+        }
+    }
+
+    /// index of an instruction in the tables of the map
+    fn index((typ, size, mode): InstructionKey) -> usize {
+        (((typ as usize) << 8) | size as usize) << 8 | mode as usize
+    }
+
+    /// address cache matching the modes of the code table
+    pub fn address_cache(&self) -> AddressCache {
+        AddressCache::new(self.near_cache_size, self.same_cache_size)
+    }
+
+    /// candidate table sizes for an instruction: the exact size first, then the explicit one
+    fn sizes(size: usize) -> [Option<u8>; 2] {
+        if size > 0 && size <= 255 {
+            [Some(size as u8), Some(0)]
+        } else {
+            [Some(0), None]
+        }
+    }
+
+    /// returns the opcode of a single instruction and whether its size must be written
+    pub fn single(&self, typ: InstructionType, size: usize, mode: u8) -> Option<(u8, bool)> {
+        OpcodeMap::sizes(size)
+            .iter()
+            .filter_map(|&s| s)
+            .filter_map(|s| self.singles[OpcodeMap::index((typ, s, mode))].map(|op| (op, s == 0)))
+            .next()
+    }
+
+    /// returns the opcode of an instruction pair and whether each size must be written
+    pub fn double(
+        &self,
+        first: (InstructionType, usize, u8),
+        second: (InstructionType, usize, u8),
+    ) -> Option<(u8, bool, bool)> {
+        for s1 in OpcodeMap::sizes(first.1).iter().filter_map(|&s| s) {
+            if !self.double_firsts[OpcodeMap::index((first.0, s1, first.2))] {
+                continue;
+            }
+            for s2 in OpcodeMap::sizes(second.1).iter().filter_map(|&s| s) {
+                let key = ((first.0, s1, first.2), (second.0, s2, second.2));
+                if let Some(&op) = self.doubles.get(&key) {
+                    return Some((op, s1 == 0, s2 == 0));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodeTable, Instruction, CODE_TABLE_SIZE};
+    use crate::Error;
+
+    #[test]
+    fn encode_decode() {
+        let bytes = CodeTable::default().encode();
+        // entry 35 is COPY with an explicit size and mode 1
+        assert_eq!(bytes[35], 3);
+        assert_eq!(bytes[35 + 512], 0);
+        assert_eq!(bytes[35 + 1024], 1);
+        // entry 163 is ADD 1 then COPY 4 with mode 0
+        assert_eq!(bytes[163 + 256], 3);
+        assert_eq!(bytes[163 + 768], 4);
+        assert_eq!(
+            CodeTable::decode(&bytes, 4, 3).unwrap(),
+            CodeTable::default()
+        );
+        match CodeTable::decode(&bytes[..CODE_TABLE_SIZE - 1], 4, 3) {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // the same modes of entries 19-162 don't exist without same cache
+        match CodeTable::decode(&bytes, 4, 0) {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // without ADD with an explicit size, some instructions can't be encoded
+        let mut no_explicit_add = bytes;
+        no_explicit_add[1] = 0;
+        match CodeTable::decode(&no_explicit_add, 4, 3) {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let explicit = CodeTable::builder()
+            .near_cache_size(1)
+            .same_cache_size(0)
+            .single(Instruction::run(0))
+            .single(Instruction::add(0))
+            .single(Instruction::copy(0, 0))
+            .single(Instruction::copy(0, 1))
+            .single(Instruction::copy(0, 2));
+        let code_table = explicit
+            .clone()
+            .double(Instruction::add(1), Instruction::copy(4, 2))
+            .build()
+            .unwrap();
+        assert_eq!(
+            code_table.entry(5),
+            Some((Instruction::add(1), Some(Instruction::copy(4, 2))))
+        );
+        assert_eq!(code_table.entry(6), None);
+        assert_eq!(
+            CodeTable::decode(&code_table.encode(), 1, 0).unwrap(),
+            code_table
+        );
+
+        // mode 3 doesn't exist without same addresses
+        match explicit.clone().single(Instruction::copy(4, 3)).build() {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // no explicit size for the near mode
+        match explicit.clone().near_cache_size(2).build() {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let too_many = (0..252).fold(explicit, |b, _| b.single(Instruction::add(1)));
+        match too_many.build() {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
     }
 }
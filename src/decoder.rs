@@ -1,226 +1,166 @@
-use address_cache::AddressCache;
-use code_table::{CodeTable, Instruction, InstructionType};
-use nom::{IResult, Needed};
+use crate::decoder_core::{DecoderCore, RawWindow, Step};
+use crate::error::Error;
+use crate::instructions::Op;
+use crate::secondary::SecondaryCompressor;
+use crate::vcdiff::{header, window_header, ChecksumDialect, VCD_TARGET};
+use nom::IResult;
+use std::cmp;
 use std::io;
 use std::io::{Read, Seek, Write};
-use std::ops::Range;
-use varint::VarIntDecode;
-use vcdiff::{header, window_header, WindowHeader, VCD_SOURCE};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq)]
 pub enum DecoderState {
     WantMoreInput,
     WantMoreInputOrDone,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum DecoderInternalState {
-    WantHeader,
-    WantWindowHeader,
-    WantWindowData,
+/// Decoder settings, including limits protecting against decompression bombs.
+///
+/// Going over any limit makes the decoder fail with `Error::LimitExceeded`.
+#[derive(Debug, Clone)]
+pub struct DecoderOptions {
+    pub(crate) max_target_window_size: u32,
+    pub(crate) max_target_size: u64,
+    pub(crate) max_delta_section_size: u32,
+    pub(crate) max_buffered_input: usize,
+    pub(crate) max_target_history: usize,
+    pub(crate) verify_checksum: bool,
+    pub(crate) checksum_dialect: Option<ChecksumDialect>,
 }
 
-pub trait ReadSlice {
-    fn read_slice(&mut self, pos: io::SeekFrom, buf: &mut [u8]) -> io::Result<()>;
+impl Default for DecoderOptions {
+    fn default() -> DecoderOptions {
+        DecoderOptions {
+            max_target_window_size: 64 << 20,
+            max_target_size: u64::MAX,
+            max_delta_section_size: 64 << 20,
+            max_buffered_input: 128 << 20,
+            max_target_history: 64 << 20,
+            verify_checksum: true,
+            checksum_dialect: None,
+        }
+    }
 }
 
-impl<T: Read + Seek> ReadSlice for T {
-    fn read_slice(&mut self, pos: io::SeekFrom, buf: &mut [u8]) -> io::Result<()> {
-        let current = self.seek(io::SeekFrom::Current(0))?;
-        self.seek(pos)?;
-        self.read_exact(buf)?;
-        self.seek(io::SeekFrom::Start(current))?;
-        Ok(())
+impl DecoderOptions {
+    pub fn new() -> DecoderOptions {
+        DecoderOptions::default()
+    }
+
+    /// maximum size of a single target window (64 MiB by default)
+    pub fn max_target_window_size(mut self, size: u32) -> DecoderOptions {
+        self.max_target_window_size = size;
+        self
+    }
+
+    /// maximum size of the whole target (unlimited by default)
+    pub fn max_target_size(mut self, size: u64) -> DecoderOptions {
+        self.max_target_size = size;
+        self
+    }
+
+    /// maximum size of the delta sections of a window, once decompressed (64 MiB by default)
+    pub fn max_delta_section_size(mut self, size: u32) -> DecoderOptions {
+        self.max_delta_section_size = size;
+        self
+    }
+
+    /// maximum amount of input kept while waiting for the end of a window (128 MiB by default)
+    pub fn max_buffered_input(mut self, size: usize) -> DecoderOptions {
+        self.max_buffered_input = size;
+        self
+    }
+
+    /// amount of decoded target kept for the windows copying from the target (VCD_TARGET),
+    /// 64 MiB by default.
+    ///
+    /// the history is trimmed once it reaches twice this size, so up to twice as much
+    /// memory is used. `decode` only keeps the target its VCD_TARGET windows refer to.
+    pub fn max_target_history(mut self, size: usize) -> DecoderOptions {
+        self.max_target_history = size;
+        self
+    }
+
+    /// enables or disables the verification of VCD_ADLER32 window checksums (enabled by default)
+    pub fn verify_checksum(mut self, verify_checksum: bool) -> DecoderOptions {
+        self.verify_checksum = verify_checksum;
+        self
+    }
+
+    /// encoding of the window checksums, by default the one of open-vcdiff for the deltas
+    /// with its 'S' version byte and the one of xdelta3 otherwise
+    pub fn checksum_dialect(mut self, dialect: ChecksumDialect) -> DecoderOptions {
+        self.checksum_dialect = Some(dialect);
+        self
     }
 }
 
-pub struct VCDiffDecoder<ORIGINAL: Read + Seek, TARGET: Write + ReadSlice> {
+pub struct VCDiffDecoder<ORIGINAL: Read + Seek, TARGET: Write> {
     original: ORIGINAL,
     target: TARGET,
-    state: DecoderInternalState,
-    code_table: CodeTable,
-    window_header: WindowHeader,
-    address_cache: AddressCache,
-    buffer: Vec<u8>,
+    core: DecoderCore,
 }
 
-impl<ORIGINAL: Read + Seek, TARGET: Write + ReadSlice> VCDiffDecoder<ORIGINAL, TARGET> {
+impl<ORIGINAL: Read + Seek, TARGET: Write> VCDiffDecoder<ORIGINAL, TARGET> {
     pub fn new(
         original: ORIGINAL,
         target: TARGET,
         buffer_size: usize,
+    ) -> VCDiffDecoder<ORIGINAL, TARGET> {
+        VCDiffDecoder::with_options(original, target, buffer_size, DecoderOptions::default())
+    }
+
+    pub fn with_options(
+        original: ORIGINAL,
+        target: TARGET,
+        buffer_size: usize,
+        options: DecoderOptions,
     ) -> VCDiffDecoder<ORIGINAL, TARGET> {
         VCDiffDecoder {
             original,
             target,
-            state: DecoderInternalState::WantHeader,
-            code_table: CodeTable::default(),
-            window_header: WindowHeader {
-                win_indicator: 0,
-                source_segment: None,
-                delta_encoding_size: 0,
-                target_window_size: 0,
-                delta_indicator: 0,
-                adds_runs_size: 0,
-                intructions_size: 0,
-                copy_addresses_size: 0,
-            },
-            buffer: Vec::with_capacity(buffer_size),
-            address_cache: AddressCache::new(4, 3),
+            core: DecoderCore::new(buffer_size, options),
         }
     }
 
-    fn decode_step<'a>(
-        &mut self,
-        input: &'a [u8],
-    ) -> Result<IResult<&'a [u8], DecoderInternalState>, io::Error> {
-        Ok(match self.state {
-            DecoderInternalState::WantHeader => match header(input) {
-                IResult::Done(remaining, header) => {
-                    if let Some(custom_code_table) = header.custom_code_table {
-                        self.code_table = custom_code_table;
-                    }
-                    IResult::Done(remaining, DecoderInternalState::WantWindowHeader)
-                }
-                IResult::Incomplete(n) => IResult::Incomplete(n),
-                IResult::Error(n) => IResult::Error(n),
-            },
-            DecoderInternalState::WantWindowHeader => match window_header(input) {
-                IResult::Done(remaining, window_header) => {
-                    self.window_header = window_header;
-                    IResult::Done(remaining, DecoderInternalState::WantWindowData)
-                }
-                IResult::Incomplete(n) => IResult::Incomplete(n),
-                IResult::Error(n) => IResult::Error(n),
-            },
-            DecoderInternalState::WantWindowData => {
-                let s1 = self.window_header.adds_runs_size as usize;
-                let s2 = s1 + self.window_header.intructions_size as usize;
-                let s3 = s2 + self.window_header.copy_addresses_size as usize;
-                let want = s3;
-                if input.len() < want {
-                    IResult::Incomplete(Needed::Size(want))
-                } else {
-                    self.decode_window(&input[0..s1], &input[s1..s2], &input[s2..s3])?;
-                    IResult::Done(&input[s3..], DecoderInternalState::WantWindowHeader)
-                }
-            }
-        })
-    }
-
-    fn decode_window(
-        &mut self,
-        adds_runs: &[u8],
-        instructions: &[u8],
-        copy_addresses: &[u8],
-    ) -> Result<(), io::Error> {
-        self.address_cache.reset();
-        let mut remaining_adds_runs = adds_runs;
-        let mut remaining_addresses = copy_addresses;
-        let mut target_data = Vec::with_capacity(self.window_header.target_window_size as usize);
-        let address_cache = &mut self.address_cache;
-        let window_header = &self.window_header;
-        let original = &mut self.original;
-        let target = &mut self.target;
-        if self.window_header.delta_indicator > 0 {
-            Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "compressed delta sections is not supported and won't be",
-            ))?;
-        }
-        {
-            let mut decode_inst = |inst: Instruction,
-                                   instructions: &[u8]|
-             -> Result<usize, io::Error> {
-                let mut size = inst.size as usize;
-                let mut remaining_instructions = instructions;
-                if size == 0 {
-                    match usize::decode_varint(remaining_instructions) {
-                        IResult::Done(r, sz) => {
-                            remaining_instructions = r;
-                            size = sz;
-                        }
-                        _ => Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            "unable to get instruction size",
-                        ))?,
-                    };
-                }
+    /// enables or disables the verification of VCD_ADLER32 window checksums (enabled by default)
+    pub fn set_verify_checksum(&mut self, verify_checksum: bool) {
+        self.core.options_mut().verify_checksum = verify_checksum;
+    }
 
-                match inst.typ {
-                    InstructionType::Add => {
-                        target_data.extend_from_slice(&remaining_adds_runs[0..size]);
-                        remaining_adds_runs = &remaining_adds_runs[size..];
-                    }
-                    InstructionType::Copy => {
-                        let source_length = window_header.source_segment.map_or(0u64, |r| r.1);
-                        let (r, addr) = address_cache.decode(
-                            (target_data.len() as u64) + source_length,
-                            inst.mode,
-                            remaining_addresses,
-                        )?;
-                        remaining_addresses = r;
-
-                        let s = window_header.source_segment.and_then(|(pos, sz)| {
-                            if addr < sz {
-                                Some(pos)
-                            } else {
-                                None
-                            }
-                        });
-
-                        if let Some(pos) = s {
-                            let target_pos = target_data.len();
-                            target_data.resize(target_pos + size, 0u8);
-                            if (window_header.win_indicator & VCD_SOURCE) > 0 {
-                                original.seek(io::SeekFrom::Start(pos + addr))?;
-                                println!("original read_exact {} {}", pos + addr, size);
-                                original
-                                    .read_exact(&mut target_data[target_pos..target_pos + size])?;
-                                println!("original read_exact {} {} done", pos + addr, size);
-                            } else {
-                                target.read_slice(
-                                    io::SeekFrom::Start(pos + addr),
-                                    &mut target_data[target_pos..target_pos + size],
-                                )?;
-                            }
-                        } else {
-                            let target_pos = (addr - source_length) as usize;
-                            // probably quite slow...
-                            for idx in target_pos..target_pos + size {
-                                let byte = target_data[idx];
-                                target_data.push(byte);
-                            }
-                        }
-                    }
-                    InstructionType::Run => {
-                        let byte = remaining_adds_runs[0];
-                        let pos = target_data.len();
-                        remaining_adds_runs = &remaining_adds_runs[1..];
-                        target_data.resize(pos + size, byte);
+    /// registers a secondary compressor, replacing any known compressor with the same id
+    pub fn add_secondary_compressor(&mut self, compressor: Box<dyn SecondaryCompressor>) {
+        self.core.add_secondary_compressor(compressor);
+    }
+
+    fn decode_window(&mut self, window: RawWindow) -> Result<(), Error> {
+        let sections = self.core.sections(window)?;
+        let (instructions, mut target_window) = self.core.window(&sections)?;
+        for op in instructions {
+            match op? {
+                Op::Add(bytes) => target_window.add(bytes),
+                Op::Run(byte, len) => target_window.run(byte, len),
+                Op::Copy { addr, len, from } => {
+                    if let Some((pos, buf)) = target_window.copy_from_segment(addr, len, from) {
+                        self.original.seek(io::SeekFrom::Start(pos))?;
+                        self.original.read_exact(buf)?;
                     }
-                };
-                Ok(instructions.len() - remaining_instructions.len())
-            };
-
-            let mut remaining_instructions = instructions;
-            while let Some((&inst_index, r)) = remaining_instructions.split_first() {
-                let e = self.code_table.entries[inst_index as usize];
-                remaining_instructions = &r;
-                remaining_instructions =
-                    &remaining_instructions[decode_inst(e.0, remaining_instructions)?..];
-                if let Some(inst) = e.1 {
-                    remaining_instructions =
-                        &remaining_instructions[decode_inst(inst, remaining_instructions)?..];
+                    target_window.copy_from_window(addr, len, from);
                 }
             }
         }
-
-        target.write(&target_data)?;
-
+        let target_data = target_window.into_data();
+        self.core.finish_window(&target_data)?;
+        self.target.write_all(&target_data)?;
         Ok(())
     }
 
+    /// application defined header of the delta (VCD_APPHEADER), available once the header is decoded
+    pub fn app_header(&self) -> Option<&[u8]> {
+        self.core.app_header()
+    }
+
     pub fn get_mut(&mut self) -> (&mut ORIGINAL, &mut TARGET) {
         (&mut self.original, &mut self.target)
     }
@@ -229,68 +169,84 @@ impl<ORIGINAL: Read + Seek, TARGET: Write + ReadSlice> VCDiffDecoder<ORIGINAL, T
         (self.original, self.target)
     }
 
-    pub fn decode(&mut self, input: &[u8]) -> Result<DecoderState, io::Error> {
-        use std::mem;
-
-        let mut res: Option<DecoderState> = None;
-        let consumed;
-        let mut buffer = Vec::new();
-        {
-            mem::swap(&mut self.buffer, &mut buffer);
-        } // little trick to make buffer and self both borrowable
-
-        {
-            let available = if buffer.len() == 0 {
-                input
-            } else {
-                buffer.extend_from_slice(&input);
-                &buffer
-            };
-            let mut remaining = available;
-            while res.is_none() {
-                match self.decode_step(remaining)? {
-                    IResult::Done(r, state) => {
-                        self.state = state;
-                        remaining = r
-                    }
-                    IResult::Incomplete(_) => {
-                        if self.state == DecoderInternalState::WantWindowHeader
-                            && remaining.len() == 0
-                        {
-                            res = Some(DecoderState::WantMoreInputOrDone)
-                        } else {
-                            res = Some(DecoderState::WantMoreInput)
-                        }
-                    }
-                    IResult::Error(_) => Err(io::Error::new(io::ErrorKind::Other, "format error"))?,
-                };
+    pub fn decode(&mut self, input: &[u8]) -> Result<DecoderState, Error> {
+        let mut buffer = self.core.take_buffer();
+        let available = if buffer.is_empty() {
+            input
+        } else {
+            buffer.extend_from_slice(input);
+            &buffer[..]
+        };
+        let mut remaining = available;
+        while let Some((r, step)) = self.core.step(remaining)? {
+            if let Step::Window(window) = step {
+                self.decode_window(window)?;
             }
-
-            consumed = available.len() - remaining.len();
+            remaining = r;
         }
+        let unconsumed = remaining.len();
+        self.core.keep_unconsumed(buffer, input, unconsumed)
+    }
+}
 
-        {
-            // Ensure remaining data is at the start of the buffer
-            if buffer.len() > 0 {
-                buffer.drain(Range {
-                    start: 0,
-                    end: consumed,
-                });
-            } else {
-                buffer.extend_from_slice(&input[consumed..]);
-            }
-            mem::swap(&mut self.buffer, &mut buffer);
+/// sum of the target window sizes announced by the windows of `delta`, and the longest
+/// stretch of target before a window that its VCD_TARGET segment goes back to
+fn target_size_hint(delta: &[u8]) -> (u64, u64) {
+    let (mut remaining, dialect) = match header(delta) {
+        IResult::Done(remaining, header) => {
+            (remaining, ChecksumDialect::from_version(header.version))
+        }
+        _ => return (0, 0),
+    };
+    let mut size = 0u64;
+    let mut history = 0u64;
+    while let IResult::Done(r, window_header) = window_header(remaining, dialect) {
+        let data_size = window_header.adds_runs_size as usize
+            + window_header.instructions_size as usize
+            + window_header.copy_addresses_size as usize;
+        if data_size > r.len() {
+            break;
+        }
+        if let (true, Some((pos, _))) = (
+            window_header.win_indicator & VCD_TARGET != 0,
+            window_header.source_segment,
+        ) {
+            history = cmp::max(history, size.saturating_sub(pos));
         }
+        size = size.saturating_add(u64::from(window_header.target_window_size));
+        remaining = &r[data_size..];
+    }
+    (size, history)
+}
 
-        Ok(res.unwrap())
+/// applies `delta` to `source` and returns the target
+pub fn decode(source: &[u8], delta: &[u8]) -> Result<Vec<u8>, Error> {
+    let (target_size, history) = target_size_hint(delta);
+    let mut target = Vec::new();
+    // the announced sizes aren't trusted yet, don't fail if they can't be allocated
+    let _ = target.try_reserve_exact(target_size as usize);
+    let mut target = io::Cursor::new(target);
+    // only the target referenced by the VCD_TARGET windows is kept
+    let mut options = DecoderOptions::default();
+    options.max_target_history = cmp::min(options.max_target_history as u64, history) as usize;
+    {
+        let mut decoder =
+            VCDiffDecoder::with_options(io::Cursor::new(source), &mut target, 0, options);
+        if decoder.decode(delta)? != DecoderState::WantMoreInputOrDone {
+            Err(Error::UnexpectedEof)?;
+        }
     }
+    Ok(target.into_inner())
 }
 
 #[cfg(test)]
 mod tests {
+    use super::decode;
+    use crate::test_util::read_file;
+    use crate::{DecoderOptions, DecoderState, Error, Limit, VCDiffDecoder};
     use std::fs::File;
-    use std::io::Read;
-    use {DecoderState, VCDiffDecoder};
+    use std::io;
+    use std::io::{Cursor, Read};
 
     #[test]
     fn text_1() {
@@ -300,6 +256,7 @@ mod tests {
             let mut decoded = File::create("tst/text-1/generated-decoded.txt").unwrap();
             let mut decoder = VCDiffDecoder::new(&mut src, &mut decoded, 128);
             let mut chunk = [0u8; 128];
+            assert_eq!(decoder.app_header(), None);
 
             let mut state: DecoderState = DecoderState::WantMoreInput;
             let mut read = patch.read(&mut chunk).unwrap();
@@ -308,21 +265,384 @@ mod tests {
                 read = patch.read(&mut chunk).unwrap();
             }
             assert_eq!(state, DecoderState::WantMoreInputOrDone);
+            let app_header = decoder.app_header().unwrap();
+            assert!(app_header.ends_with(b"\\LICENSE/"));
         }
+        assert_eq!(
+            read_file("tst/text-1/generated-decoded.txt"),
+            read_file("tst/text-1/target.txt")
+        );
+    }
+
+    #[test]
+    fn text_1_checksum_mismatch() {
+        let mut src = read_file("tst/text-1/src.txt");
+        let pos = src.windows(8).position(|w| w == b"Everyone").unwrap();
+        src[pos] = b'e';
+        let patch = read_file("tst/text-1/l.patch");
+
+        let mut decoded = Cursor::new(Vec::new());
         {
-            let mut target = File::open("tst/text-1/target.txt").unwrap();
-            let mut target_data = Vec::new();
-            assert!(
-                target.read_to_end(&mut target_data).is_ok(),
-                "target should be read to end"
+            let mut decoder = VCDiffDecoder::new(Cursor::new(&src), &mut decoded, 128);
+            match decoder.decode(&patch) {
+                Err(Error::ChecksumMismatch { .. }) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+        {
+            let mut decoder = VCDiffDecoder::new(Cursor::new(&src), &mut decoded, 128);
+            decoder.set_verify_checksum(false);
+            assert_eq!(
+                decoder.decode(&patch).unwrap(),
+                DecoderState::WantMoreInputOrDone
             );
-            let mut decoded = File::open("tst/text-1/generated-decoded.txt").unwrap();
-            let mut decoded_data = Vec::new();
-            assert!(
-                decoded.read_to_end(&mut decoded_data).is_ok(),
-                "decoded should be read to end"
+        }
+    }
+
+    #[test]
+    fn errors() {
+        fn decode(source: &[u8], delta: &[u8]) -> Result<DecoderState, Error> {
+            let mut decoded = Cursor::new(Vec::new());
+            let mut decoder = VCDiffDecoder::new(Cursor::new(source), &mut decoded, 128);
+            decoder.decode(delta)
+        }
+
+        match decode(b"", b"\xD6\xC3\xC5\x00\x00") {
+            Err(Error::InvalidMagic) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match decode(b"", b"\xD6\xC3\xC4\x01\x00") {
+            Err(Error::InvalidMagic) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match decode(b"", b"\xD6\xC3\xC4\x00\x00\x00\x05\x00\x08\x00\x00\x00") {
+            Err(Error::InvalidDeltaIndicator(0x08)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let patch = read_file("tst/text-1/l.patch");
+        match decode(b"", &patch) {
+            Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let err: io::Error = decode(b"", b"\xD6\xC3\xC5\x00").unwrap_err().into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupted_patches() {
+        let src = read_file("tst/text-1/src.txt");
+        let patch = read_file("tst/text-1/l.patch");
+
+        let decode = |delta: &[u8]| {
+            let mut decoded = Cursor::new(Vec::new());
+            let mut decoder = VCDiffDecoder::new(Cursor::new(&src), &mut decoded, 128);
+            decoder.set_verify_checksum(false);
+            let _ = decoder.decode(delta);
+        };
+        for len in (0..patch.len()).step_by(97) {
+            decode(&patch[..len]);
+        }
+        for pos in (0..256).chain((256..patch.len()).step_by(13)) {
+            for &mask in &[0x01, 0x80, 0xFF] {
+                let mut corrupted = patch.clone();
+                corrupted[pos] ^= mask;
+                decode(&corrupted);
+            }
+        }
+    }
+
+    fn decode_with_options(options: DecoderOptions) -> Result<DecoderState, Error> {
+        let mut src = File::open("tst/text-1/src.txt").unwrap();
+        let patch = read_file("tst/text-1/l.patch");
+        let mut decoded = Cursor::new(Vec::new());
+        let mut decoder = VCDiffDecoder::with_options(&mut src, &mut decoded, 128, options);
+        decoder.decode(&patch)
+    }
+
+    #[test]
+    fn limits() {
+        let limit_exceeded = |options, expected| match decode_with_options(options) {
+            Err(Error::LimitExceeded(limit)) => assert_eq!(limit, expected),
+            res => panic!("unexpected result {:?}", res),
+        };
+        limit_exceeded(
+            DecoderOptions::new().max_target_window_size(1024),
+            Limit::TargetWindowSize,
+        );
+        limit_exceeded(
+            DecoderOptions::new().max_target_size(1024),
+            Limit::TargetSize,
+        );
+        limit_exceeded(
+            DecoderOptions::new().max_delta_section_size(16),
+            Limit::DeltaSectionSize,
+        );
+        assert_eq!(
+            decode_with_options(DecoderOptions::new()).unwrap(),
+            DecoderState::WantMoreInputOrDone
+        );
+
+        // a window header announcing a huge window is rejected before any allocation
+        let mut delta = vec![0xD6, 0xC3, 0xC4, 0x00, 0x00, 0x00];
+        delta.extend_from_slice(&[0x10, 0x8F, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x00, 0x00]);
+        let mut decoder = VCDiffDecoder::new(Cursor::new(Vec::new()), Cursor::new(Vec::new()), 128);
+        match decoder.decode(&delta) {
+            Err(Error::LimitExceeded(Limit::TargetWindowSize)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // the end of a window that can't be decoded yet is buffered, up to the limit
+        let options = DecoderOptions::new().max_buffered_input(64);
+        let mut decoder = VCDiffDecoder::with_options(
+            Cursor::new(Vec::new()),
+            Cursor::new(Vec::new()),
+            128,
+            options,
+        );
+        // window of 1000 bytes of added data
+        let delta = [
+            0xD6, 0xC3, 0xC4, 0x00, 0x00, 0x00, 0x87, 0x6E, 0x87, 0x68, 0x00, 0x87,
+        ];
+        assert_eq!(decoder.decode(&delta).unwrap(), DecoderState::WantMoreInput);
+        assert_eq!(
+            decoder.decode(&[0x68, 0x00, 0x00]).unwrap(),
+            DecoderState::WantMoreInput
+        );
+        match decoder.decode(&[0; 100]) {
+            Err(Error::LimitExceeded(Limit::BufferedInput)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn one_shot() {
+        let source = read_file("tst/text-1/src.txt");
+        let patch = read_file("tst/text-1/l.patch");
+        let target = read_file("tst/text-1/target.txt");
+        assert_eq!(super::target_size_hint(&patch), (target.len() as u64, 0));
+        assert_eq!(decode(&source, &patch).unwrap(), target);
+        match decode(&source, &patch[..patch.len() - 1]) {
+            Err(Error::UnexpectedEof) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    /// a window of `target_window_size` bytes with the given sections
+    fn window(
+        win_indicator: u8,
+        segment: &[u8],
+        target_window_size: usize,
+        data: &[u8],
+        inst: &[u8],
+        addr: &[u8],
+    ) -> Vec<u8> {
+        use crate::varint::VarIntEncode;
+
+        let mut window = vec![win_indicator];
+        window.extend_from_slice(segment);
+        let mut rest: Vec<u8> = target_window_size.encode_varint().collect();
+        rest.push(0);
+        rest.extend(data.len().encode_varint());
+        rest.extend(inst.len().encode_varint());
+        rest.extend(addr.len().encode_varint());
+        rest.extend_from_slice(data);
+        rest.extend_from_slice(inst);
+        rest.extend_from_slice(addr);
+        window.extend(rest.len().encode_varint());
+        window.extend_from_slice(&rest);
+        window
+    }
+
+    /// a window adding `data`
+    fn add_window(data: &[u8]) -> Vec<u8> {
+        use crate::varint::VarIntEncode;
+
+        let mut add = vec![1u8];
+        add.extend(data.len().encode_varint());
+        window(0, &[], data.len(), data, &add, &[])
+    }
+
+    /// a VCD_TARGET window copying `len` bytes of the target at `pos`
+    fn target_window(pos: usize, len: usize) -> Vec<u8> {
+        use crate::varint::VarIntEncode;
+
+        let mut segment: Vec<u8> = len.encode_varint().collect();
+        segment.extend(pos.encode_varint());
+        let mut copy = vec![19u8];
+        copy.extend(len.encode_varint());
+        window(0x02, &segment, len, &[], &copy, &[0])
+    }
+
+    /// a window adding `data`, then a VCD_TARGET window copying it back from the target
+    fn target_windows_delta(data: &[u8]) -> Vec<u8> {
+        let mut delta = vec![0xD6, 0xC3, 0xC4, 0x00, 0x00];
+        delta.extend(add_window(data));
+        delta.extend(target_window(0, data.len()));
+        delta
+    }
+
+    #[test]
+    fn target_windows() {
+        let data = b"hello world, ";
+        let delta = target_windows_delta(data);
+
+        // the target is only written to
+        let mut target = Vec::new();
+        {
+            let mut decoder = VCDiffDecoder::new(Cursor::new(Vec::new()), &mut target, 0);
+            assert_eq!(
+                decoder.decode(&delta).unwrap(),
+                DecoderState::WantMoreInputOrDone
             );
-            assert_eq!(decoded_data, target_data);
+        }
+        assert_eq!(target, b"hello world, hello world, ");
+
+        let options = DecoderOptions::new().max_target_history(4);
+        let mut decoder =
+            VCDiffDecoder::with_options(Cursor::new(Vec::new()), Vec::new(), 0, options);
+        match decoder.decode(&delta) {
+            Err(Error::LimitExceeded(Limit::TargetHistory)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn target_history() {
+        // 3 byte windows, the history is trimmed as they are decoded
+        let mut delta = vec![0xD6, 0xC3, 0xC4, 0x00, 0x00];
+        let mut target = Vec::new();
+        for idx in 0..10u8 {
+            let data = [idx, idx + 1, idx + 2];
+            delta.extend(add_window(&data));
+            target.extend_from_slice(&data);
+        }
+        let decode_with = |max_target_history: usize, pos: usize, len: usize| {
+            let mut delta = delta.clone();
+            delta.extend(target_window(pos, len));
+            let options = DecoderOptions::new().max_target_history(max_target_history);
+            let mut decoder =
+                VCDiffDecoder::with_options(Cursor::new(Vec::new()), Vec::new(), 0, options);
+            decoder.decode(&delta)?;
+            Ok::<_, Error>(decoder.into_inner().1)
+        };
+        let decoded = decode_with(8, 22, 8).unwrap();
+        assert_eq!(decoded[..30], target[..]);
+        assert_eq!(decoded[30..], target[22..]);
+        match decode_with(8, 21, 8) {
+            Err(Error::LimitExceeded(Limit::TargetHistory)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // `decode` keeps the history the VCD_TARGET windows need
+        delta.extend(target_window(1, 29));
+        assert_eq!(super::target_size_hint(&delta), (59, 29));
+        let decoded = decode(b"", &delta).unwrap();
+        assert_eq!(decoded[30..], target[1..]);
+    }
+
+    #[test]
+    fn interleaved() {
+        let source = b"hello world";
+        let mut delta = vec![0xD6, 0xC3, 0xC4, b'S', 0x00];
+        // VCD_SOURCE window on the whole source, with empty data and addresses sections
+        delta.extend_from_slice(&[0x01, 11, 0, 17, 22, 0x00, 0, 12, 0]);
+        // COPY 11 at 0, RUN 4 '!', ADD 2 "ab", then ADD 1 "x" + COPY 4 at 6
+        delta.extend_from_slice(&[19, 11, 0, 0, 4, b'!', 3, b'a', b'b', 163, b'x', 6]);
+        assert_eq!(decode(source, &delta).unwrap(), b"hello world!!!!abxworl");
+
+        // RFC 3284 deltas keep the data and the addresses in their own sections
+        delta[3] = 0x00;
+        match decode(source, &delta) {
+            Err(Error::InvalidCopyAddress) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn checksum_dialects() {
+        use crate::adler32::adler32;
+        use crate::varint::VarIntEncode;
+        use crate::ChecksumDialect;
+
+        let source = b"hello world";
+        // VCD_SOURCE | VCD_ADLER32 window copying the whole source
+        let delta = |version: u8, checksum: &[u8]| {
+            let mut delta = vec![0xD6, 0xC3, 0xC4, version, 0x00, 0x05, 11, 0];
+            delta.push(8 + checksum.len() as u8);
+            delta.extend_from_slice(&[11, 0x00, 0, 2, 1]);
+            delta.extend_from_slice(checksum);
+            delta.extend_from_slice(&[19, 11, 0]);
+            delta
+        };
+        let decode_with = |delta: &[u8], options: DecoderOptions| {
+            let mut decoder =
+                VCDiffDecoder::with_options(Cursor::new(&source[..]), Vec::new(), 0, options);
+            decoder.decode(delta).map(|_| decoder.into_inner().1)
+        };
+        let varint: Vec<u8> = adler32(source).encode_varint().collect();
+        let be = adler32(source).to_be_bytes();
+
+        assert_eq!(decode(source, &delta(b'S', &varint)).unwrap(), source);
+        assert_eq!(decode(source, &delta(0x00, &be)).unwrap(), source);
+        let options = DecoderOptions::new().checksum_dialect(ChecksumDialect::OpenVcdiff);
+        assert_eq!(decode_with(&delta(0x00, &varint), options).unwrap(), source);
+        let options = DecoderOptions::new().checksum_dialect(ChecksumDialect::Xdelta3);
+        assert!(decode_with(&delta(b'S', &varint), options).is_err());
+    }
+
+    #[cfg(feature = "encoder")]
+    #[test]
+    fn custom_code_table() {
+        use crate::code_table::{CodeTable, Instruction};
+        use crate::varint::VarIntEncode;
+
+        // RUN 3 instead of ADD 1, with a single near address and no same address: the
+        // opcodes of the other modes are NOOPs
+        let mut code_table = CodeTable::default();
+        code_table.entries[2] = Some((Instruction::run(3), None));
+        for entry in code_table.entries.iter_mut() {
+            if let Some((first, second)) = *entry {
+                if Some(first).iter().chain(&second).any(|inst| inst.mode > 2) {
+                    *entry = None;
+                }
+            }
+        }
+        let table_delta = crate::encode(&CodeTable::default().encode(), &code_table.encode());
+        let delta = |table_delta: &[u8], copy_opcode: u8| {
+            let mut delta = vec![0xD6, 0xC3, 0xC4, 0x00, 0x02];
+            delta.extend((table_delta.len() + 2).encode_varint());
+            delta.extend_from_slice(&[1, 0]);
+            delta.extend_from_slice(table_delta);
+            delta.extend_from_slice(&[0x00, 16, 11, 0x00, 5, 3, 1]);
+            // ADD 4, COPY 4 with the given mode, RUN 3
+            delta.extend_from_slice(b"abcdz");
+            delta.extend_from_slice(&[5, copy_opcode, 2, 0]);
+            delta
+        };
+
+        // mode 2 is the near address 0
+        assert_eq!(
+            decode(b"", &delta(&table_delta, 52)).unwrap(),
+            b"abcdabcdzzz"
+        );
+        // mode 3 no longer exists
+        match decode(b"", &delta(&table_delta, 68)) {
+            Err(Error::InvalidInstruction) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let mut corrupted = table_delta.clone();
+        corrupted[0] ^= 0xFF;
+        match decode(b"", &delta(&corrupted, 52)) {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // the entries of the default table use modes up to 8
+        let default = CodeTable::default().encode();
+        let table_delta = crate::encode(&default, &default);
+        match decode(b"", &delta(&table_delta, 52)) {
+            Err(Error::InvalidCodeTable) => {}
+            res => panic!("unexpected result {:?}", res),
         }
     }
 }
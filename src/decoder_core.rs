@@ -0,0 +1,496 @@
+use crate::adler32::adler32;
+use crate::code_table::{CodeTable, CODE_TABLE_SIZE};
+use crate::decoder::{DecoderOptions, DecoderState, VCDiffDecoder};
+use crate::error::{Error, Limit};
+use crate::instructions::{CopyFrom, Instructions};
+use crate::secondary::{builtin_compressors, SecondaryCompressor};
+use crate::varint::VarIntDecode;
+use crate::vcdiff::{
+    header, is_flag_set, window_header, ChecksumDialect, CodeTableData, WindowHeader, VCDIFF_MAGIC,
+    VCD_ADDRCOMP, VCD_CODETABLE, VCD_DATACOMP, VCD_INSTCOMP, VCD_SOURCE, VCD_TARGET,
+    VCD_VERSION_RFC, VCD_VERSION_SDCH,
+};
+use nom::IResult;
+use std::borrow::Cow;
+use std::cmp;
+use std::io;
+use std::mem;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum DecoderInternalState {
+    WantHeader,
+    WantWindowHeader,
+    WantWindowData,
+}
+
+/// what a decoding step parsed
+pub enum Step<'a> {
+    Header,
+    WindowHeader,
+    /// the data of a window, to be decoded by the caller
+    Window(RawWindow<'a>),
+}
+
+/// the three sections of a window, as found in the delta
+pub struct RawWindow<'a> {
+    adds_runs: &'a [u8],
+    instructions: &'a [u8],
+    copy_addresses: &'a [u8],
+}
+
+/// the three sections of a window, once decompressed
+pub struct Sections<'a> {
+    adds_runs: Cow<'a, [u8]>,
+    instructions: Cow<'a, [u8]>,
+    copy_addresses: Cow<'a, [u8]>,
+    /// whether the sizes, added data and addresses are interleaved with the instructions
+    interleaved: bool,
+}
+
+impl<'a> Sections<'a> {
+    pub fn instructions<'s>(
+        &'s self,
+        code_table: &'s CodeTable,
+        window_header: &WindowHeader,
+    ) -> Instructions<'s> {
+        Instructions::new(
+            code_table,
+            window_header,
+            &self.adds_runs,
+            &self.instructions,
+            &self.copy_addresses,
+            self.interleaved,
+        )
+    }
+}
+
+/// target window being reconstructed from the instructions of its window
+pub struct TargetWindow<'a> {
+    data: Vec<u8>,
+    window_header: &'a WindowHeader,
+    target_history: &'a [u8],
+    history_start: u64,
+}
+
+impl<'a> TargetWindow<'a> {
+    pub fn add(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn run(&mut self, byte: u8, len: usize) {
+        let pos = self.data.len();
+        self.data.resize(pos + len, byte);
+    }
+
+    /// appends the part of a COPY coming from the source segment.
+    ///
+    /// when the segment is in the source file, returns its position there and the
+    /// buffer the caller must fill from it.
+    pub fn copy_from_segment(
+        &mut self,
+        addr: u64,
+        len: usize,
+        from: CopyFrom,
+    ) -> Option<(u64, &mut [u8])> {
+        let (pos, sz) = self.window_header.source_segment?;
+        if from != CopyFrom::Source {
+            return None;
+        }
+        // copies may continue from the end of the segment into the target window
+        let from_segment = cmp::min(len as u64, sz - addr) as usize;
+        let start = self.data.len();
+        let segment_pos = pos + addr;
+        if is_flag_set(self.window_header.win_indicator, VCD_SOURCE) {
+            self.data.resize(start + from_segment, 0u8);
+            Some((segment_pos, &mut self.data[start..]))
+        } else {
+            let history_pos = (segment_pos - self.history_start) as usize;
+            self.data
+                .extend_from_slice(&self.target_history[history_pos..history_pos + from_segment]);
+            None
+        }
+    }
+
+    /// appends the part of a COPY coming from the target window itself
+    pub fn copy_from_window(&mut self, addr: u64, len: usize, from: CopyFrom) {
+        let (target_pos, size) = match from {
+            CopyFrom::Source => {
+                let sz = self.window_header.source_segment.map_or(0, |s| s.1);
+                (0, len - cmp::min(len as u64, sz - addr) as usize)
+            }
+            CopyFrom::Target => (addr as usize, len),
+        };
+        // source and target may overlap, bytes have to be copied one by one
+        for idx in target_pos..target_pos + size {
+            let byte = self.data[idx];
+            self.data.push(byte);
+        }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// decodes a custom code table (VCD_CODETABLE), the delta of its byte layout against
+/// the one of the default code table
+fn decode_code_table(data: &CodeTableData) -> Result<CodeTable, Error> {
+    // the table of the delta is the default one, which keeps the recursion bounded
+    if !matches!(data.delta.get(4), Some(&hdr) if !is_flag_set(hdr, VCD_CODETABLE)) {
+        Err(Error::InvalidCodeTable)?;
+    }
+    let options = DecoderOptions::new()
+        .max_target_window_size(CODE_TABLE_SIZE as u32)
+        .max_target_size(CODE_TABLE_SIZE as u64);
+    let default = CodeTable::default().encode();
+    let mut bytes = Vec::with_capacity(CODE_TABLE_SIZE);
+    let mut decoder =
+        VCDiffDecoder::with_options(io::Cursor::new(&default[..]), &mut bytes, 0, options);
+    match decoder.decode(&data.delta) {
+        Ok(DecoderState::WantMoreInputOrDone) => {}
+        _ => Err(Error::InvalidCodeTable)?,
+    }
+    CodeTable::decode(&bytes, data.near_cache_size, data.same_cache_size)
+}
+
+/// I/O free part of the decoders: parses the delta, keeps the state between windows
+/// and checks the decoded windows.
+pub struct DecoderCore {
+    state: DecoderInternalState,
+    version: u8,
+    code_table: CodeTable,
+    window_header: WindowHeader,
+    secondary_compressors: Vec<Box<dyn SecondaryCompressor>>,
+    secondary_compressor: Option<usize>,
+    custom_code_table: bool,
+    options: DecoderOptions,
+    target_size: u64,
+    /// last decoded bytes of the target, for VCD_TARGET windows. Up to twice
+    /// `max_target_history` bytes are kept, so it is trimmed once per
+    /// `max_target_history` bytes decoded instead of at every window.
+    target_history: Vec<u8>,
+    app_header: Option<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl DecoderCore {
+    pub fn new(buffer_size: usize, options: DecoderOptions) -> DecoderCore {
+        DecoderCore {
+            state: DecoderInternalState::WantHeader,
+            version: VCD_VERSION_RFC,
+            code_table: CodeTable::default(),
+            window_header: WindowHeader {
+                win_indicator: 0,
+                source_segment: None,
+                delta_encoding_size: 0,
+                target_window_size: 0,
+                delta_indicator: 0,
+                adds_runs_size: 0,
+                instructions_size: 0,
+                copy_addresses_size: 0,
+                adler32: None,
+            },
+            buffer: Vec::with_capacity(buffer_size),
+            secondary_compressors: builtin_compressors(),
+            secondary_compressor: None,
+            custom_code_table: false,
+            options,
+            target_size: 0,
+            target_history: Vec::new(),
+            app_header: None,
+        }
+    }
+
+    pub fn options_mut(&mut self) -> &mut DecoderOptions {
+        &mut self.options
+    }
+
+    pub fn add_secondary_compressor(&mut self, compressor: Box<dyn SecondaryCompressor>) {
+        let id = compressor.id();
+        self.secondary_compressors.retain(|c| c.id() != id);
+        self.secondary_compressors.push(compressor);
+    }
+
+    pub fn app_header(&self) -> Option<&[u8]> {
+        self.app_header.as_deref()
+    }
+
+    /// version byte of the header
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// encoding of the window checksums, set by the options or deduced from the version
+    fn checksum_dialect(&self) -> ChecksumDialect {
+        self.options
+            .checksum_dialect
+            .unwrap_or_else(|| ChecksumDialect::from_version(self.version))
+    }
+
+    pub fn code_table(&self) -> &CodeTable {
+        &self.code_table
+    }
+
+    /// whether the header replaced the default code table (VCD_CODETABLE)
+    pub fn has_custom_code_table(&self) -> bool {
+        self.custom_code_table
+    }
+
+    /// id of the secondary compressor of the header (VCD_DECOMPRESS)
+    pub fn secondary_compressor_id(&self) -> Option<u8> {
+        self.secondary_compressor
+            .map(|idx| self.secondary_compressors[idx].id())
+    }
+
+    /// header of the last parsed window
+    pub fn window_header(&self) -> &WindowHeader {
+        &self.window_header
+    }
+
+    /// returns the input waiting to be decoded, `keep_unconsumed` gives it back
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        mem::take(&mut self.buffer)
+    }
+
+    /// keeps the last `unconsumed` bytes of the input for the next call.
+    ///
+    /// `buffer` is the buffer returned by `take_buffer`, with `input` appended if
+    /// it wasn't empty.
+    pub fn keep_unconsumed(
+        &mut self,
+        mut buffer: Vec<u8>,
+        input: &[u8],
+        unconsumed: usize,
+    ) -> Result<DecoderState, Error> {
+        if unconsumed > self.options.max_buffered_input {
+            Err(Error::LimitExceeded(Limit::BufferedInput))?;
+        }
+        if buffer.is_empty() {
+            buffer.extend_from_slice(&input[input.len() - unconsumed..]);
+        } else {
+            let consumed = buffer.len() - unconsumed;
+            buffer.drain(..consumed);
+        }
+        self.buffer = buffer;
+        Ok(self.input_state(unconsumed))
+    }
+
+    /// state of the decoder once the input is parsed, except its last `unconsumed` bytes
+    pub fn input_state(&self, unconsumed: usize) -> DecoderState {
+        if self.state == DecoderInternalState::WantWindowHeader && unconsumed == 0 {
+            DecoderState::WantMoreInputOrDone
+        } else {
+            DecoderState::WantMoreInput
+        }
+    }
+
+    /// parses the next header, window header or window data, returns None if more input
+    /// is needed
+    pub fn step<'a>(&mut self, input: &'a [u8]) -> Result<Option<(&'a [u8], Step<'a>)>, Error> {
+        Ok(match self.state {
+            DecoderInternalState::WantHeader => match header(input) {
+                IResult::Done(remaining, header) => {
+                    self.version = header.version;
+                    if let Some(id) = header.secondary_compressor_id {
+                        let idx = self.secondary_compressors.iter().position(|c| c.id() == id);
+                        if idx.is_none() {
+                            Err(Error::UnsupportedSecondaryCompressor(id))?;
+                        }
+                        self.secondary_compressor = idx;
+                    }
+                    if let Some(data) = header.custom_code_table {
+                        self.code_table = decode_code_table(&data)?;
+                        self.custom_code_table = true;
+                    }
+                    self.app_header = header.app_header;
+                    self.state = DecoderInternalState::WantWindowHeader;
+                    Some((remaining, Step::Header))
+                }
+                IResult::Incomplete(_) => None,
+                IResult::Error(_) => {
+                    let magic_len = 3.min(input.len());
+                    if input[..magic_len] != VCDIFF_MAGIC[..magic_len] {
+                        Err(Error::InvalidMagic)?;
+                    }
+                    if let Some(&version) = input.get(3) {
+                        if version != VCD_VERSION_RFC && version != VCD_VERSION_SDCH {
+                            Err(Error::InvalidMagic)?;
+                        }
+                    }
+                    Err(Error::InvalidHeader)?
+                }
+            },
+            DecoderInternalState::WantWindowHeader => {
+                match window_header(input, self.checksum_dialect()) {
+                    IResult::Done(remaining, window_header) => {
+                        let both = VCD_SOURCE | VCD_TARGET;
+                        if window_header.win_indicator & both == both {
+                            Err(Error::InvalidWindowHeader)?;
+                        }
+                        if let Some((pos, sz)) = window_header.source_segment {
+                            let end = pos.checked_add(sz).ok_or(Error::InvalidWindowHeader)?;
+                            if is_flag_set(window_header.win_indicator, VCD_TARGET)
+                                && end > self.target_size
+                            {
+                                Err(Error::InvalidWindowHeader)?;
+                            }
+                        }
+                        self.check_limits(&window_header)?;
+                        self.window_header = window_header;
+                        self.state = DecoderInternalState::WantWindowData;
+                        Some((remaining, Step::WindowHeader))
+                    }
+                    IResult::Incomplete(_) => None,
+                    IResult::Error(_) => Err(Error::InvalidWindowHeader)?,
+                }
+            }
+            DecoderInternalState::WantWindowData => {
+                let s1 = self.window_header.adds_runs_size as usize;
+                let s2 = s1 + self.window_header.instructions_size as usize;
+                let s3 = s2 + self.window_header.copy_addresses_size as usize;
+                if input.len() < s3 {
+                    None
+                } else {
+                    self.state = DecoderInternalState::WantWindowHeader;
+                    let window = RawWindow {
+                        adds_runs: &input[0..s1],
+                        instructions: &input[s1..s2],
+                        copy_addresses: &input[s2..s3],
+                    };
+                    Some((&input[s3..], Step::Window(window)))
+                }
+            }
+        })
+    }
+
+    fn check_limits(&self, window_header: &WindowHeader) -> Result<(), Error> {
+        let options = &self.options;
+        let target_window_size = window_header.target_window_size;
+        if target_window_size > options.max_target_window_size {
+            Err(Error::LimitExceeded(Limit::TargetWindowSize))?;
+        }
+        if u64::from(target_window_size) > options.max_target_size - self.target_size {
+            Err(Error::LimitExceeded(Limit::TargetSize))?;
+        }
+        let delta_section_size = u64::from(window_header.adds_runs_size)
+            + u64::from(window_header.instructions_size)
+            + u64::from(window_header.copy_addresses_size);
+        if delta_section_size > u64::from(options.max_delta_section_size) {
+            Err(Error::LimitExceeded(Limit::DeltaSectionSize))?;
+        }
+        Ok(())
+    }
+
+    /// returns the decompressed content of a section flagged by `flag` in the Delta_Indicator
+    fn decompress_section<'a>(&self, section: &'a [u8], flag: u8) -> Result<Cow<'a, [u8]>, Error> {
+        let delta_indicator = self.window_header.delta_indicator;
+        if !is_flag_set(delta_indicator, flag) {
+            return Ok(Cow::Borrowed(section));
+        }
+        let compressor = match self.secondary_compressor {
+            Some(idx) => &self.secondary_compressors[idx],
+            None => Err(Error::InvalidDeltaIndicator(delta_indicator))?,
+        };
+        let (compressed, size) = match usize::decode_varint(section) {
+            IResult::Done(r, sz) => (r, sz),
+            _ => Err(Error::SecondaryDecompression(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid decompressed section size",
+            )))?,
+        };
+        if size > self.options.max_delta_section_size as usize {
+            Err(Error::LimitExceeded(Limit::DeltaSectionSize))?;
+        }
+        let decompressed = compressor
+            .decompress(compressed, size)
+            .map_err(Error::SecondaryDecompression)?;
+        if decompressed.len() != size {
+            Err(Error::SecondaryDecompression(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed section size mismatch",
+            )))?;
+        }
+        Ok(Cow::Owned(decompressed))
+    }
+
+    pub fn sections<'a>(&self, window: RawWindow<'a>) -> Result<Sections<'a>, Error> {
+        let delta_indicator = self.window_header.delta_indicator;
+        if delta_indicator & !(VCD_DATACOMP | VCD_INSTCOMP | VCD_ADDRCOMP) > 0 {
+            Err(Error::InvalidDeltaIndicator(delta_indicator))?;
+        }
+        // open-vcdiff leaves the data and addresses sections empty when it interleaves
+        // them with the instructions
+        let interleaved = self.version == VCD_VERSION_SDCH
+            && self.window_header.adds_runs_size == 0
+            && self.window_header.copy_addresses_size == 0;
+        Ok(Sections {
+            adds_runs: self.decompress_section(window.adds_runs, VCD_DATACOMP)?,
+            instructions: self.decompress_section(window.instructions, VCD_INSTCOMP)?,
+            copy_addresses: self.decompress_section(window.copy_addresses, VCD_ADDRCOMP)?,
+            interleaved,
+        })
+    }
+
+    /// returns the instructions of the current window and the target window they build
+    pub fn window<'a>(
+        &'a self,
+        sections: &'a Sections<'_>,
+    ) -> Result<(Instructions<'a>, TargetWindow<'a>), Error> {
+        let window_header = &self.window_header;
+        let history_len = cmp::min(self.target_history.len(), self.options.max_target_history);
+        let target_history = &self.target_history[self.target_history.len() - history_len..];
+        let history_start = self.target_size - history_len as u64;
+        if let Some((pos, _)) = window_header.source_segment {
+            if is_flag_set(window_header.win_indicator, VCD_TARGET) && pos < history_start {
+                Err(Error::LimitExceeded(Limit::TargetHistory))?;
+            }
+        }
+        let target_window = TargetWindow {
+            data: Vec::with_capacity(window_header.target_window_size as usize),
+            window_header,
+            target_history,
+            history_start,
+        };
+        Ok((
+            sections.instructions(&self.code_table, window_header),
+            target_window,
+        ))
+    }
+
+    /// accounts for a window parsed without being decoded
+    pub fn skip_window(&mut self) {
+        self.target_size += u64::from(self.window_header.target_window_size);
+        self.target_history.clear();
+    }
+
+    /// checks a decoded target window before it is written to the target
+    pub fn finish_window(&mut self, target_data: &[u8]) -> Result<(), Error> {
+        let window_header = &self.window_header;
+        if target_data.len() != window_header.target_window_size as usize {
+            Err(Error::InvalidInstruction)?;
+        }
+
+        if let (true, Some(expected)) = (self.options.verify_checksum, window_header.adler32) {
+            let actual = adler32(target_data);
+            if actual != expected {
+                Err(Error::ChecksumMismatch { expected, actual })?;
+            }
+        }
+
+        self.target_size += target_data.len() as u64;
+        let max_target_history = self.options.max_target_history;
+        if target_data.len() >= max_target_history {
+            self.target_history.clear();
+            let start = target_data.len() - max_target_history;
+            self.target_history.extend_from_slice(&target_data[start..]);
+        } else {
+            self.target_history.extend_from_slice(target_data);
+            if self.target_history.len() >= max_target_history.saturating_mul(2) {
+                let excess = self.target_history.len() - max_target_history;
+                self.target_history.drain(..excess);
+            }
+        }
+        Ok(())
+    }
+}
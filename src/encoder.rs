@@ -1,13 +1,26 @@
-use rolling_hash::RollingHash;
+use crate::code_table::{CodeTable, OpcodeMap};
+use crate::optimal_parser::{optimal_parse, NICE_MATCH_LENGTH};
+use crate::rolling_hash::RollingHash;
+use crate::suffix_array::SuffixMatcher;
+use crate::varint::VarIntEncode;
+use crate::vcdiff::{
+    WindowHeader, VCDIFF_MAGIC, VCD_APPHEADER, VCD_CODETABLE, VCD_SOURCE, VCD_TARGET,
+};
+use crate::window_writer::{WindowSections, WindowWriter};
+use std::borrow::Cow;
 use std::cmp;
+use std::collections::BTreeMap;
 use std::io;
-use std::io::{BufReader, Read, Seek, Write};
+use std::io::{Read, Seek, Write};
 use std::mem;
+use std::ops::Range;
 
 /// cpu/memory efficient hashmap from hash_value to multiple window indexes
 /// window hashes must be inserted backward
 pub struct WindowHashMap {
     window_size: usize,
+    /// distance between the starts of the indexed windows, a multiple of `window_size`
+    stride: usize,
     current_window_index: usize,
     /// hashed_value => window_index
     table: Vec<usize>,
@@ -27,7 +40,7 @@ impl<'a> Iterator for Matches<'a> {
         if self.window_index > 0 {
             let real_window_index = self.window_index - 1;
             self.window_index = self.hash_map.next_window_indexes[real_window_index];
-            Some(real_window_index as u64 * self.hash_map.window_size as u64)
+            Some(real_window_index as u64 * self.hash_map.stride as u64)
         } else {
             None
         }
@@ -35,34 +48,62 @@ impl<'a> Iterator for Matches<'a> {
 }
 
 impl WindowHashMap {
-    fn new(file_size: u64, window_size: usize, hash_size: usize) -> WindowHashMap {
-        let mut table = Vec::with_capacity(hash_size);
-        table.resize(hash_size, 0);
-
-        let indexes_size = (file_size / (window_size as u64)) as usize;
-        let mut next_window_indexes = Vec::with_capacity(indexes_size);
-        next_window_indexes.resize(indexes_size, 0);
+    fn new(file_size: u64, window_size: usize, max_memory: usize) -> WindowHashMap {
+        let (stride, indexes_size, hash_size) =
+            WindowHashMap::layout(file_size, window_size, max_memory);
+        let table = vec![0; hash_size];
+        let next_window_indexes = vec![0; indexes_size];
 
         WindowHashMap {
             window_size,
+            stride,
             current_window_index: indexes_size,
             table,
             next_window_indexes,
         }
     }
 
+    /// number of windows starting every `stride` bytes in the file
+    fn indexes_size(file_size: u64, window_size: usize, stride: usize) -> usize {
+        if file_size < window_size as u64 {
+            0
+        } else {
+            ((file_size - window_size as u64) / stride as u64) as usize + 1
+        }
+    }
+
+    /// returns the stride, the number of windows and the table size of the index of a file.
+    ///
+    /// the table has about one entry per window. When the index doesn't fit in `max_memory`
+    /// bytes, the stride is doubled until it does: one window out of two, four, ... is indexed.
+    fn layout(file_size: u64, window_size: usize, max_memory: usize) -> (usize, usize, usize) {
+        let mut stride = window_size;
+        loop {
+            let indexes_size = WindowHashMap::indexes_size(file_size, window_size, stride);
+            let hash_size = cmp::min(
+                cmp::max(indexes_size, 1).next_power_of_two(),
+                MAX_HASH_TABLE_SIZE,
+            );
+            let memory = (hash_size + indexes_size).saturating_mul(mem::size_of::<usize>());
+            if memory <= max_memory || indexes_size <= 1 {
+                return (stride, indexes_size, hash_size);
+            }
+            stride *= 2;
+        }
+    }
+
     fn prepend_window(&mut self, hash_value: u32) {
         assert!(self.current_window_index > 0);
         let table_index = (hash_value as usize) % self.table.len();
         let found_window_index = &mut self.table[table_index];
         if *found_window_index > 0 {
-            self.next_window_indexes[self.current_window_index] = *found_window_index;
+            self.next_window_indexes[self.current_window_index - 1] = *found_window_index;
         }
         *found_window_index = self.current_window_index;
         self.current_window_index -= 1;
     }
 
-    fn find_matches<'a>(&'a self, hash_value: u32) -> Matches<'a> {
+    fn find_matches(&self, hash_value: u32) -> Matches<'_> {
         let table_index = (hash_value as usize) % self.table.len();
         let found_window_index = self.table[table_index];
         Matches {
@@ -72,36 +113,569 @@ impl WindowHashMap {
     }
 }
 
+/// the rolling hash values are lower than 2^23, a larger table would never be filled
+static MAX_HASH_TABLE_SIZE: usize = 1 << 23;
+
+/// size of the reads of the source while indexing it
+static INDEX_BUFFER_SIZE: usize = 32768;
+
+/// size of the blocks read from the source while extending matches
+static SOURCE_BLOCK_SIZE: u64 = 1 << 16;
+
+/// random access to the source file through a one block cache
+struct SourceReader<R: Read + Seek> {
+    inner: R,
+    len: u64,
+    block: Vec<u8>,
+    block_pos: u64,
+}
+
+impl<R: Read + Seek> SourceReader<R> {
+    fn new(mut inner: R) -> Result<SourceReader<R>, io::Error> {
+        let len = inner.seek(io::SeekFrom::End(0))?;
+        Ok(SourceReader {
+            inner,
+            len,
+            block: Vec::new(),
+            block_pos: 0,
+        })
+    }
+
+    /// returns the cached bytes starting at `pos`, `pos` must be lower than the source length
+    fn bytes_at(&mut self, pos: u64) -> Result<&[u8], io::Error> {
+        if pos < self.block_pos || pos >= self.block_pos + self.block.len() as u64 {
+            let block_pos = pos - pos % SOURCE_BLOCK_SIZE;
+            let block_len = cmp::min(SOURCE_BLOCK_SIZE, self.len - block_pos);
+            self.block.resize(block_len as usize, 0);
+            self.inner.seek(io::SeekFrom::Start(block_pos))?;
+            self.inner.read_exact(&mut self.block)?;
+            self.block_pos = block_pos;
+        }
+        Ok(&self.block[(pos - self.block_pos) as usize..])
+    }
+
+    /// reads the bytes of `range`, without caching them
+    fn read_range(&mut self, range: &Range<u64>) -> Result<Vec<u8>, io::Error> {
+        let mut bytes = vec![0; (range.end - range.start) as usize];
+        self.inner.seek(io::SeekFrom::Start(range.start))?;
+        self.inner.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// number of bytes of `data` that match the source starting at `pos`, up to `end`
+    fn match_length(&mut self, pos: u64, data: &[u8], end: u64) -> Result<usize, io::Error> {
+        let mut len = 0;
+        while len < data.len() && pos + (len as u64) < end {
+            let bytes = self.bytes_at(pos + len as u64)?;
+            let bytes = &bytes[..cmp::min(bytes.len() as u64, end - pos - len as u64) as usize];
+            let expected = cmp::min(bytes.len(), data.len() - len);
+            let matching = bytes
+                .iter()
+                .zip(&data[len..])
+                .take_while(|&(a, b)| a == b)
+                .count();
+            len += matching;
+            if matching < expected {
+                break;
+            }
+        }
+        Ok(len)
+    }
+
+    /// number of bytes at the end of `data` that match the source just before `pos`, down
+    /// to `start`
+    fn match_length_backward(
+        &mut self,
+        pos: u64,
+        data: &[u8],
+        start: u64,
+    ) -> Result<usize, io::Error> {
+        let mut len = 0;
+        while len < data.len() && (len as u64) < pos - start {
+            if self.bytes_at(pos - len as u64 - 1)?[0] != data[data.len() - len - 1] {
+                break;
+            }
+            len += 1;
+        }
+        Ok(len)
+    }
+}
+
+/// A verified match of the target window
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Match {
+    /// address of the match in the source segment + target window address space
+    pub(crate) addr: u64,
+    pub(crate) target_pos: usize,
+    pub(crate) len: usize,
+}
+
+/// finds the matches of the target window in the source segment of a VCD_SOURCE window
+struct SourceMatcher<'a, R: Read + Seek> {
+    hash_map: &'a WindowHashMap,
+    source: &'a mut SourceReader<R>,
+    segment: Range<u64>,
+    /// number of candidates of the hash chain checked for each block
+    max_chain_depth: usize,
+}
+
+impl<'a, R: Read + Seek> SourceMatcher<'a, R> {
+    /// finds the longest match of the target block at `pos`.
+    ///
+    /// matches are extended backward down to `min_pos`, the start of the unmatched data.
+    fn find_match(
+        &mut self,
+        hash_value: u32,
+        target: &[u8],
+        pos: usize,
+        min_pos: usize,
+    ) -> Result<Option<Match>, io::Error> {
+        let segment = &self.segment;
+        let mut best: Option<Match> = None;
+        let candidates = self.hash_map.find_matches(hash_value);
+        for candidate in candidates.take(self.max_chain_depth) {
+            if !segment.contains(&candidate) {
+                continue;
+            }
+            let forward = self
+                .source
+                .match_length(candidate, &target[pos..], segment.end)?;
+            if forward < self.hash_map.window_size {
+                continue; // hash collision
+            }
+            let backward = self.source.match_length_backward(
+                candidate,
+                &target[min_pos..pos],
+                segment.start,
+            )?;
+            if best.as_ref().is_none_or(|m| forward + backward > m.len) {
+                best = Some(Match {
+                    addr: candidate - backward as u64 - segment.start,
+                    target_pos: pos - backward,
+                    len: forward + backward,
+                });
+            }
+            // a long match ends the search: each candidate is compared up to the end of
+            // its match, which is slow on repetitive data
+            if forward >= NICE_MATCH_LENGTH {
+                break;
+            }
+        }
+        Ok(best)
+    }
+}
+
+/// finds the matches of the target window in the data before each position: the start
+/// of the target window and, for VCD_TARGET windows, the target segment preceding it.
+struct SelfMatcher {
+    /// hash value => position + 1 of the last block inserted with this hash value
+    table: Vec<usize>,
+    window_size: usize,
+}
+
+impl SelfMatcher {
+    fn new(data_len: usize, window_size: usize) -> SelfMatcher {
+        let table_size = cmp::max(data_len / window_size, 1).next_power_of_two();
+        SelfMatcher {
+            table: vec![0; table_size],
+            window_size,
+        }
+    }
+
+    fn insert(&mut self, hash_value: u32, pos: usize) {
+        let table_index = hash_value as usize & (self.table.len() - 1);
+        self.table[table_index] = pos + 1;
+    }
+
+    /// finds the match of the block at `pos` in `data`, extended backward down to
+    /// `min_pos`.
+    ///
+    /// `offset` is the address of the start of `data`. The match may overlap the block,
+    /// as the decoder copies the bytes one by one.
+    fn find_match(
+        &self,
+        hash_value: u32,
+        data: &[u8],
+        offset: u64,
+        pos: usize,
+        min_pos: usize,
+    ) -> Option<Match> {
+        let table_index = hash_value as usize & (self.table.len() - 1);
+        let candidate = self.table[table_index].checked_sub(1)?;
+        let forward = data[candidate..]
+            .iter()
+            .zip(&data[pos..])
+            .take_while(|&(a, b)| a == b)
+            .count();
+        if forward < self.window_size {
+            return None; // hash collision
+        }
+        let mut backward = 0;
+        while backward < pos - min_pos
+            && backward < candidate
+            && data[candidate - backward - 1] == data[pos - backward - 1]
+        {
+            backward += 1;
+        }
+        Some(Match {
+            addr: offset + (candidate - backward) as u64,
+            target_pos: pos - backward,
+            len: forward + backward,
+        })
+    }
+}
+
+/// the match finder of a window
+enum WindowMatcher<'a, R: Read + Seek> {
+    /// hashes of the source segment blocks and of the window blocks before each position
+    Hash {
+        source: Option<SourceMatcher<'a, R>>,
+        window: SelfMatcher,
+    },
+    /// suffix array of the source segment followed by the target window, where the
+    /// positions are the addresses
+    SuffixArray {
+        matcher: SuffixMatcher,
+        window_size: usize,
+    },
+}
+
+impl<'a, R: Read + Seek> WindowMatcher<'a, R> {
+    /// longest match of the block at `pos` of `data`, in the source segment or before
+    /// `pos`, extended backward down to `min_pos`.
+    ///
+    /// `offset` is the address of the start of `data`.
+    fn find_match(
+        &mut self,
+        hash_value: u32,
+        data: &[u8],
+        offset: u64,
+        pos: usize,
+        min_pos: usize,
+    ) -> Result<Option<Match>, io::Error> {
+        match self {
+            WindowMatcher::Hash { source, window, .. } => {
+                let found = window.find_match(hash_value, data, offset, pos, min_pos);
+                if let Some(source) = source {
+                    if let Some(m) = source.find_match(hash_value, data, pos, min_pos)? {
+                        if found.as_ref().is_none_or(|f| m.len > f.len) {
+                            return Ok(Some(m));
+                        }
+                    }
+                }
+                Ok(found)
+            }
+            WindowMatcher::SuffixArray {
+                matcher,
+                window_size,
+            } => {
+                let text_pos = offset as usize + pos;
+                let (candidate, forward) = match matcher.longest_match(text_pos) {
+                    Some((candidate, forward)) if forward >= *window_size => (candidate, forward),
+                    _ => return Ok(None),
+                };
+                let text = matcher.text();
+                let mut backward = 0;
+                while backward < pos - min_pos
+                    && backward < candidate
+                    && text[candidate - backward - 1] == text[text_pos - backward - 1]
+                {
+                    backward += 1;
+                }
+                Ok(Some(Match {
+                    addr: (candidate - backward) as u64,
+                    target_pos: pos - backward,
+                    len: forward + backward,
+                }))
+            }
+        }
+    }
+
+    /// indexes the block at `pos`, which was searched
+    fn insert(&mut self, hash_value: u32, pos: usize) {
+        if let WindowMatcher::Hash { window, .. } = self {
+            window.insert(hash_value, pos);
+        }
+    }
+
+    /// takes the matches of the target window at `start` of `data` as they are found, or
+    /// lazily: a match is dropped when the next position has a longer one.
+    ///
+    /// `offset` is the address of the start of `data`.
+    fn parse(
+        &mut self,
+        rolling_hash: &RollingHash,
+        data: &[u8],
+        offset: u64,
+        start: usize,
+        lazy_matching: bool,
+        min_match_length: usize,
+    ) -> Result<Vec<Match>, io::Error> {
+        let window_size = rolling_hash.window_size();
+        let mut copies = Vec::new();
+        let mut unmatched_pos = start;
+        let mut pos = start;
+        let mut hash_value = None;
+        while pos + window_size <= data.len() {
+            let h = hash_value.unwrap_or_else(|| rolling_hash.hash(&data[pos..pos + window_size]));
+            let mut found = self.find_match(h, data, offset, pos, unmatched_pos)?;
+            self.insert(h, pos);
+            if let (true, Some(m)) = (lazy_matching, &found) {
+                if pos + 1 + window_size <= data.len() {
+                    let next_h = rolling_hash.shift(h, data[pos], data[pos + window_size]);
+                    let next = self.find_match(next_h, data, offset, pos + 1, unmatched_pos)?;
+                    if next.as_ref().is_some_and(|next| next.len > m.len) {
+                        found = next;
+                    }
+                }
+            }
+            if let Some(m) = found.filter(|m| m.len >= min_match_length) {
+                copies.push(m);
+                pos = m.target_pos + m.len;
+                unmatched_pos = pos;
+                hash_value = None;
+            } else {
+                if pos + window_size < data.len() {
+                    hash_value = Some(rolling_hash.shift(h, data[pos], data[pos + window_size]));
+                }
+                pos += 1;
+            }
+        }
+        Ok(copies)
+    }
+}
+
+/// number of parts of a source segment when counting the target blocks found in them
+static SEGMENT_PARTS: u64 = 16;
+
+/// source candidates of each target block counted when choosing a source segment
+static SEGMENT_VOTE_CANDIDATES: usize = 4;
+
+/// picks the source segment of `size` bytes where most blocks of `target` are found
+fn choose_segment(
+    hash_map: &WindowHashMap,
+    rolling_hash: &RollingHash,
+    source_len: u64,
+    size: u64,
+    target: &[u8],
+) -> Range<u64> {
+    if size == 0 || source_len <= size {
+        return 0..cmp::min(size, source_len);
+    }
+    // the blocks are counted by parts of segment to bound the memory used
+    let part_size = cmp::max(size / SEGMENT_PARTS, 1);
+    let mut votes = BTreeMap::new();
+    let window_size = rolling_hash.window_size();
+    let mut hash_value = None;
+    for pos in 0..(target.len() + 1).saturating_sub(window_size) {
+        let h = match hash_value {
+            None => rolling_hash.hash(&target[..window_size]),
+            Some(h) => rolling_hash.shift(h, target[pos - 1], target[pos - 1 + window_size]),
+        };
+        hash_value = Some(h);
+        for candidate in hash_map.find_matches(h).take(SEGMENT_VOTE_CANDIDATES) {
+            *votes.entry(candidate / part_size).or_insert(0u64) += 1;
+        }
+    }
+
+    // best run of consecutive parts fitting in the segment
+    let parts: Vec<(u64, u64)> = votes.into_iter().collect();
+    let parts_per_segment = size / part_size;
+    let mut best = (0, 0);
+    let mut first = 0;
+    let mut count = 0;
+    for &(part, part_votes) in &parts {
+        count += part_votes;
+        while part - parts[first].0 >= parts_per_segment {
+            count -= parts[first].1;
+            first += 1;
+        }
+        if count > best.0 {
+            best = (count, parts[first].0);
+        }
+    }
+    let start = cmp::min(best.1 * part_size, source_len - size);
+    start..start + size
+}
+
+/// algorithms searching the matches of the target windows
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatchFinder {
+    /// hashes of the source blocks aligned on `diff_window_size`, with a bounded memory use
+    RollingHash,
+    /// suffix array of each source segment and target window, finding the longest match
+    /// at every position. It keeps about 40 bytes per byte of the segment and the window
+    /// in memory, it is meant for files of a few megabytes.
+    SuffixArray,
+}
+
+/// presets of the match search settings of `EncoderOptions`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// checks few candidates and ignores the short matches
+    Fast,
+    /// the default settings
+    Default,
+    /// checks more candidates and chooses the matches by their encoded size
+    Best,
+}
+
+/// hash chain candidates checked by default, and by `CompressionLevel::Default`
+static DEFAULT_MAX_CHAIN_DEPTH: usize = 128;
+
+/// hash chain candidates checked by `CompressionLevel::Best`
+static BEST_MAX_CHAIN_DEPTH: usize = 4096;
+
+/// Encoder settings: the size of the windows, bounding the memory needed to decode them,
+/// the effort spent searching matches and the memory of the source index.
+#[derive(Debug, Clone)]
+pub struct EncoderOptions {
+    pub(crate) max_target_window_size: u32,
+    pub(crate) source_segment_size: u64,
+    pub(crate) max_chain_depth: usize,
+    pub(crate) min_match_length: usize,
+    pub(crate) lazy_matching: bool,
+    pub(crate) match_finder: MatchFinder,
+    pub(crate) optimal_parsing: bool,
+    pub(crate) max_index_memory: usize,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions {
+            max_target_window_size: 64 << 20,
+            source_segment_size: u64::MAX,
+            max_chain_depth: DEFAULT_MAX_CHAIN_DEPTH,
+            min_match_length: 0,
+            lazy_matching: false,
+            match_finder: MatchFinder::RollingHash,
+            optimal_parsing: false,
+            max_index_memory: 1 << 30,
+        }
+    }
+}
+
+impl EncoderOptions {
+    pub fn new() -> EncoderOptions {
+        EncoderOptions::default()
+    }
+
+    /// maximum size of the target windows (64 MiB by default, the default limit of the
+    /// decoder)
+    ///
+    /// # Panics
+    ///
+    /// panics if `size` is 0
+    pub fn max_target_window_size(mut self, size: u32) -> EncoderOptions {
+        assert!(size > 0);
+        self.max_target_window_size = size;
+        self
+    }
+
+    /// maximum size of the source segment of each window (the whole source by default).
+    ///
+    /// each window uses the segment of the source where most of its blocks are found.
+    pub fn source_segment_size(mut self, size: u64) -> EncoderOptions {
+        self.source_segment_size = size;
+        self
+    }
+
+    /// sets the match search settings of `level`
+    pub fn compression_level(self, level: CompressionLevel) -> EncoderOptions {
+        let (max_chain_depth, min_match_length, lazy_matching, optimal_parsing) = match level {
+            CompressionLevel::Fast => (8, 32, false, false),
+            CompressionLevel::Default => (DEFAULT_MAX_CHAIN_DEPTH, 0, false, false),
+            CompressionLevel::Best => (BEST_MAX_CHAIN_DEPTH, 0, true, true),
+        };
+        self.max_chain_depth(max_chain_depth)
+            .min_match_length(min_match_length)
+            .lazy_matching(lazy_matching)
+            .optimal_parsing(optimal_parsing)
+    }
+
+    /// maximum number of source blocks with the same hash checked for each target
+    /// position (128 by default)
+    pub fn max_chain_depth(mut self, depth: usize) -> EncoderOptions {
+        self.max_chain_depth = depth;
+        self
+    }
+
+    /// shortest match copied instead of added, matches are never shorter than the
+    /// hashed blocks (0 by default)
+    pub fn min_match_length(mut self, len: usize) -> EncoderOptions {
+        self.min_match_length = len;
+        self
+    }
+
+    /// enables lazy matching: a match is dropped when the next position has a longer
+    /// one (disabled by default)
+    pub fn lazy_matching(mut self, lazy_matching: bool) -> EncoderOptions {
+        self.lazy_matching = lazy_matching;
+        self
+    }
+
+    /// sets the algorithm searching the matches (`MatchFinder::RollingHash` by default)
+    pub fn match_finder(mut self, match_finder: MatchFinder) -> EncoderOptions {
+        self.match_finder = match_finder;
+        self
+    }
+
+    /// enables optimal parsing: the matches are searched at every position and the
+    /// instructions of each window are chosen by their encoded size with the code table,
+    /// instead of taking the matches as they are found (disabled by default).
+    ///
+    /// the window is parsed by segments of 4 KiB, see `optimal_parse`: only the matches
+    /// of a segment are kept in memory.
+    pub fn optimal_parsing(mut self, optimal_parsing: bool) -> EncoderOptions {
+        self.optimal_parsing = optimal_parsing;
+        self
+    }
+
+    /// maximum memory used by the index of the source blocks, 1 GiB by default.
+    ///
+    /// the index takes 16 to 24 bytes per `diff_window_size` bytes of source. Over the
+    /// budget, only one block out of two, four, ... is indexed: the matches shorter than
+    /// the distance between the indexed blocks may be missed.
+    pub fn max_index_memory(mut self, bytes: usize) -> EncoderOptions {
+        self.max_index_memory = bytes;
+        self
+    }
+}
+
 pub struct VCDiffEncoder<OLD: Read + Seek, NEW: Read + Seek> {
-    diff_window_size: usize,
-    old: OLD,
+    rolling_hash: RollingHash,
+    old: SourceReader<OLD>,
     old_hash_map: WindowHashMap,
     new: NEW,
-    new_hash_map: WindowHashMap,
+    opcodes: OpcodeMap,
+    options: EncoderOptions,
+    code_table: Option<CodeTable>,
+    app_header: Option<Vec<u8>>,
 }
 
 fn hash_map<F: Read + Seek>(
     file: &mut F,
     rolling_hash: &RollingHash,
+    max_memory: usize,
 ) -> Result<WindowHashMap, io::Error> {
     let file_size = file.seek(io::SeekFrom::End(0))?;
     let diff_window_size = rolling_hash.window_size();
-    let diff_window_size_u64 = diff_window_size as u64;
-    let mut hash_map = WindowHashMap::new(file_size, diff_window_size, 1 << 28 /* 268 MB */);
-    let mut position = file_size - (file_size % diff_window_size_u64);
-    let mut buffer = [0u8; 32768];
-    let buffer_len = buffer.len() - buffer.len() % diff_window_size; // force alignment
-    while position > 0 {
-        let read_size = cmp::min(position, buffer_len as u64);
-        let mut read_size_usize = read_size as usize;
-        file.seek(io::SeekFrom::Start(position - read_size))?;
-        file.read(&mut buffer[0..read_size_usize])?;
-        position -= read_size;
-        while read_size_usize > 0 {
-            read_size_usize -= diff_window_size;
-            let h = rolling_hash.hash(&buffer[read_size_usize..diff_window_size]);
+    let mut hash_map = WindowHashMap::new(file_size, diff_window_size, max_memory);
+    let stride = hash_map.stride;
+    // windows hashed per read, starting every `stride` bytes
+    let windows_per_read = INDEX_BUFFER_SIZE.saturating_sub(diff_window_size) / stride + 1;
+    let mut buffer = vec![0u8; (windows_per_read - 1) * stride + diff_window_size];
+    let mut end = hash_map.current_window_index;
+    while end > 0 {
+        let first = end.saturating_sub(windows_per_read);
+        let read_size = (end - first - 1) * stride + diff_window_size;
+        file.seek(io::SeekFrom::Start(first as u64 * stride as u64))?;
+        file.read_exact(&mut buffer[0..read_size])?;
+        for idx in (0..end - first).rev() {
+            let h = rolling_hash.hash(&buffer[idx * stride..idx * stride + diff_window_size]);
             hash_map.prepend_window(h);
         }
+        end = first;
     }
     file.seek(io::SeekFrom::Start(0))?;
 
@@ -110,20 +684,585 @@ fn hash_map<F: Read + Seek>(
 
 impl<OLD: Read + Seek, NEW: Read + Seek> VCDiffEncoder<OLD, NEW> {
     pub fn new(
+        old: OLD,
+        new: NEW,
+        diff_window_size: usize,
+    ) -> Result<VCDiffEncoder<OLD, NEW>, io::Error> {
+        VCDiffEncoder::with_options(old, new, diff_window_size, EncoderOptions::default())
+    }
+
+    pub fn with_options(
         mut old: OLD,
-        mut new: NEW,
+        new: NEW,
         diff_window_size: usize,
+        options: EncoderOptions,
     ) -> Result<VCDiffEncoder<OLD, NEW>, io::Error> {
         assert!(diff_window_size >= 4);
         let rolling_hash = RollingHash::new(diff_window_size);
-        let old_hash_map = hash_map(&mut old, &rolling_hash)?;
-        let new_hash_map = hash_map(&mut new, &rolling_hash)?;
+        let old_hash_map = hash_map(&mut old, &rolling_hash, options.max_index_memory)?;
         Ok(VCDiffEncoder {
-            diff_window_size,
-            old,
+            rolling_hash,
+            old: SourceReader::new(old)?,
             old_hash_map,
             new,
-            new_hash_map,
+            opcodes: OpcodeMap::new(&CodeTable::default()),
+            options,
+            code_table: None,
+            app_header: None,
         })
     }
+
+    /// encodes the windows with `code_table` instead of the default one, the table is
+    /// written in the delta header (VCD_CODETABLE)
+    pub fn set_code_table(&mut self, code_table: CodeTable) {
+        self.opcodes = OpcodeMap::new(&code_table);
+        self.code_table = Some(code_table);
+    }
+
+    /// sets the application defined data written in the delta header (VCD_APPHEADER)
+    pub fn set_app_header(&mut self, app_header: Vec<u8>) {
+        self.app_header = Some(app_header);
+    }
+
+    /// writes the delta that turns the old file into the new file
+    pub fn encode(&mut self, mut out: impl Write) -> Result<(), io::Error> {
+        let mut hdr_indicator = 0;
+        let mut header_data = Vec::new();
+        if let Some(ref code_table) = self.code_table {
+            hdr_indicator |= VCD_CODETABLE;
+            // the table is written as a delta against the default one
+            let table_delta = encode(&CodeTable::default().encode(), &code_table.encode());
+            header_data.extend((table_delta.len() + 2).encode_varint());
+            header_data.push(code_table.near_cache_size());
+            header_data.push(code_table.same_cache_size());
+            header_data.extend_from_slice(&table_delta);
+        }
+        if let Some(ref app_header) = self.app_header {
+            hdr_indicator |= VCD_APPHEADER;
+            header_data.extend(app_header.len().encode_varint());
+            header_data.extend_from_slice(app_header);
+        }
+        let mut header = VCDIFF_MAGIC.to_vec();
+        header.push(hdr_indicator);
+        header.extend_from_slice(&header_data);
+        out.write_all(&header)?;
+
+        let max_target_window_size = u64::from(self.options.max_target_window_size);
+        // end of the target already encoded, kept for the VCD_TARGET windows
+        let history_size = cmp::min(self.options.source_segment_size, max_target_window_size);
+        let mut history = Vec::new();
+        let mut target_offset = 0;
+        let mut target = Vec::new();
+        self.new.seek(io::SeekFrom::Start(0))?;
+        loop {
+            target.clear();
+            (&mut self.new)
+                .take(max_target_window_size)
+                .read_to_end(&mut target)?;
+            if target.is_empty() {
+                return Ok(());
+            }
+            self.encode_window(&target, &history, target_offset, &mut out)?;
+            target_offset += target.len() as u64;
+            if self.old.len == 0 {
+                history.extend_from_slice(&target);
+                let excess = history.len().saturating_sub(history_size as usize);
+                history.drain(..excess);
+            }
+        }
+    }
+
+    /// encodes the target window starting at `target_offset` in the target, `history`
+    /// is the target just before it.
+    fn encode_window<W: Write>(
+        &mut self,
+        target: &[u8],
+        history: &[u8],
+        target_offset: u64,
+        out: &mut W,
+    ) -> Result<(), io::Error> {
+        // without source, the windows copy from the previous ones instead (VCD_TARGET)
+        let (win_indicator, segment) = if self.old.len > 0 {
+            let segment = choose_segment(
+                &self.old_hash_map,
+                &self.rolling_hash,
+                self.old.len,
+                self.options.source_segment_size,
+                target,
+            );
+            (VCD_SOURCE, segment)
+        } else {
+            (
+                VCD_TARGET,
+                target_offset - history.len() as u64..target_offset,
+            )
+        };
+        let source_length = segment.end - segment.start;
+
+        // the target window, after the target segment for VCD_TARGET windows
+        let data = if win_indicator == VCD_TARGET && !history.is_empty() {
+            Cow::Owned([history, target].concat())
+        } else {
+            Cow::Borrowed(target)
+        };
+        let start = data.len() - target.len();
+        let offset = source_length - start as u64;
+
+        let window_size = self.rolling_hash.window_size();
+        let mut matcher = match self.options.match_finder {
+            MatchFinder::RollingHash => {
+                // blocks of the target segment, before the target window
+                let mut window = SelfMatcher::new(data.len(), window_size);
+                for pos in (0..(start + 1).saturating_sub(window_size)).step_by(window_size) {
+                    let h = self.rolling_hash.hash(&data[pos..pos + window_size]);
+                    window.insert(h, pos);
+                }
+                let source = if win_indicator == VCD_SOURCE {
+                    Some(SourceMatcher {
+                        hash_map: &self.old_hash_map,
+                        source: &mut self.old,
+                        segment: segment.clone(),
+                        max_chain_depth: self.options.max_chain_depth,
+                    })
+                } else {
+                    None
+                };
+                WindowMatcher::Hash { source, window }
+            }
+            MatchFinder::SuffixArray => {
+                let text = if win_indicator == VCD_SOURCE {
+                    [&self.old.read_range(&segment)?[..], &data].concat()
+                } else {
+                    data.to_vec()
+                };
+                WindowMatcher::SuffixArray {
+                    matcher: SuffixMatcher::new(text),
+                    window_size,
+                }
+            }
+        };
+        let options = &self.options;
+        let rolling_hash = &self.rolling_hash;
+        let opcodes = &self.opcodes;
+        let window_header = |sections: &WindowSections| {
+            let mut header = WindowHeader {
+                win_indicator: if source_length > 0 { win_indicator } else { 0 },
+                source_segment: if source_length > 0 {
+                    Some((segment.start, source_length))
+                } else {
+                    None
+                },
+                delta_encoding_size: 0,
+                target_window_size: target.len() as u32,
+                delta_indicator: 0,
+                adds_runs_size: sections.adds_runs.len() as u32,
+                instructions_size: sections.instructions.len() as u32,
+                copy_addresses_size: sections.copy_addresses.len() as u32,
+                adler32: None,
+            };
+            header.delta_encoding_size = header.compute_delta_encoding_size();
+            header
+        };
+        let write_sections = |copies: &[Match]| {
+            let mut writer = WindowWriter::new(opcodes, source_length);
+            let mut unmatched_pos = start;
+            for m in copies {
+                writer.add(&data[unmatched_pos..m.target_pos]);
+                writer.copy(m.addr, m.len);
+                unmatched_pos = m.target_pos + m.len;
+            }
+            writer.add(&data[unmatched_pos..]);
+            writer.finish()
+        };
+
+        let copies = if options.optimal_parsing {
+            // hash of the block at the previous position searched
+            let mut hash_value: Option<(usize, u32)> = None;
+            optimal_parse(
+                opcodes,
+                &data,
+                source_length,
+                start,
+                options.min_match_length,
+                |pos, min_pos| {
+                    if pos + window_size > data.len() {
+                        return Ok(None);
+                    }
+                    let h = match hash_value {
+                        Some((previous, h)) if previous + 1 == pos => {
+                            rolling_hash.shift(h, data[previous], data[pos + window_size - 1])
+                        }
+                        _ => rolling_hash.hash(&data[pos..pos + window_size]),
+                    };
+                    hash_value = Some((pos, h));
+                    let found = matcher.find_match(h, &data, offset, pos, min_pos)?;
+                    matcher.insert(h, pos);
+                    Ok(found)
+                },
+            )?
+        } else {
+            matcher.parse(
+                rolling_hash,
+                &data,
+                offset,
+                start,
+                options.lazy_matching,
+                options.min_match_length,
+            )?
+        };
+        let sections = write_sections(&copies);
+        let header = window_header(&sections);
+        let mut header_bytes = Vec::new();
+        header.encode(&mut header_bytes);
+        out.write_all(&header_bytes)?;
+        out.write_all(&sections.adds_runs)?;
+        out.write_all(&sections.instructions)?;
+        out.write_all(&sections.copy_addresses)?;
+        Ok(())
+    }
+}
+
+/// returns a delta turning `source` into `target`
+pub fn encode(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    VCDiffEncoder::new(io::Cursor::new(source), io::Cursor::new(target), 16)
+        .and_then(|mut encoder| encoder.encode(&mut delta))
+        .expect("in-memory encoding failed");
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WindowHashMap;
+    use crate::test_util::read_file;
+    use crate::vcdiff::VCD_TARGET;
+    use crate::{
+        decode, encode, windows, CodeTable, CompressionLevel, CopyFrom, DecoderState,
+        EncoderOptions, Instruction, MatchFinder, Op, VCDiffDecoder, VCDiffEncoder,
+    };
+    use std::io::Cursor;
+
+    /// encodes `target` against `source` and checks the delta decodes back to it
+    fn encode_with(
+        source: &[u8],
+        target: &[u8],
+        diff_window_size: usize,
+        options: EncoderOptions,
+    ) -> Vec<u8> {
+        let mut delta = Vec::new();
+        let mut encoder = VCDiffEncoder::with_options(
+            Cursor::new(source),
+            Cursor::new(target),
+            diff_window_size,
+            options,
+        )
+        .unwrap();
+        encoder.encode(&mut delta).unwrap();
+        assert_eq!(decode(source, &delta).unwrap(), target);
+        delta
+    }
+
+    #[test]
+    fn text_1() {
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        let delta = encode_with(&source, &target, 16, EncoderOptions::new());
+        assert!(delta.len() < target.len());
+    }
+
+    #[test]
+    fn without_source() {
+        let mut target = b"header".to_vec();
+        target.extend_from_slice(&[0u8; 1000]);
+        target.extend_from_slice(b"trailer");
+        let delta = encode_with(b"", &target, 16, EncoderOptions::new());
+        assert!(delta.len() < 64);
+        encode_with(b"", b"", 16, EncoderOptions::new());
+    }
+
+    #[test]
+    fn app_header() {
+        let target = b"target";
+        let mut delta = Vec::new();
+        let mut encoder = VCDiffEncoder::new(Cursor::new(b""), Cursor::new(target), 16).unwrap();
+        encoder.set_app_header(b"version 2".to_vec());
+        encoder.encode(&mut delta).unwrap();
+
+        let mut decoded = Cursor::new(Vec::new());
+        {
+            let mut decoder = VCDiffDecoder::new(Cursor::new(b""), &mut decoded, 128);
+            assert_eq!(
+                decoder.decode(&delta).unwrap(),
+                DecoderState::WantMoreInputOrDone
+            );
+            assert_eq!(decoder.app_header(), Some(&b"version 2"[..]));
+        }
+        assert_eq!(decoded.into_inner(), target);
+    }
+
+    #[test]
+    fn custom_code_table() {
+        let mut builder = CodeTable::builder()
+            .near_cache_size(2)
+            .same_cache_size(1)
+            .single(Instruction::run(0));
+        for size in 0..17 {
+            builder = builder.single(Instruction::add(size));
+        }
+        for mode in 0..5 {
+            builder = builder.single(Instruction::copy(0, mode));
+        }
+        for size in 4..21 {
+            builder = builder.single(Instruction::copy(size, 0));
+        }
+        for size in 1..5 {
+            builder = builder.double(Instruction::add(size), Instruction::copy(4, 0));
+        }
+        let code_table = builder.build().unwrap();
+
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        let mut delta = Vec::new();
+        let mut encoder =
+            VCDiffEncoder::new(Cursor::new(&source), Cursor::new(&target), 16).unwrap();
+        encoder.set_code_table(code_table);
+        encoder.encode(&mut delta).unwrap();
+
+        let mut windows = windows(&delta);
+        assert!(windows.next().unwrap().is_ok());
+        assert!(windows.has_custom_code_table());
+        assert_eq!(decode(&source, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn segment_end() {
+        // the block at 2 of the target matches the source up to its end, past the segment
+        let source = [69, 155, 178, 19, 41, 77, 29, 173, 32, 200, 223];
+        let target = [178, 24, 19, 41, 77, 29, 173, 32, 200, 223, 1, 2];
+        let options = EncoderOptions::new().source_segment_size(6);
+        let delta = encode_with(&source, &target, 4, options);
+        for window in windows(&delta) {
+            let window = window.unwrap();
+            let (_, segment_len) = window.header().source_segment.unwrap();
+            for op in window.instructions() {
+                if let Op::Copy {
+                    addr,
+                    len,
+                    from: CopyFrom::Source,
+                } = op.unwrap()
+                {
+                    assert!(addr + len as u64 <= segment_len);
+                }
+            }
+        }
+
+        // targets made of pieces of the source, with small segments
+        let mut seed = 5u32;
+        let mut random = |max: usize| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as usize % max
+        };
+        for _ in 0..200 {
+            let source: Vec<u8> = (0..random(300) + 1).map(|_| random(4) as u8).collect();
+            let mut target = Vec::new();
+            for _ in 0..random(8) {
+                let start = random(source.len());
+                let end = start + random(source.len() - start + 1);
+                target.extend_from_slice(&source[start..end]);
+                target.push(random(4) as u8);
+            }
+            let segment_size = random(source.len()) as u64 + 1;
+            let options = EncoderOptions::new().source_segment_size(segment_size);
+            encode_with(&source, &target, 4, options);
+        }
+    }
+
+    #[test]
+    fn multiple_windows() {
+        // pseudo random source, the target takes each of its windows from another part
+        let mut seed = 1u32;
+        let source: Vec<u8> = (0..20000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut target = Vec::new();
+        for idx in (0..10).rev() {
+            target.extend_from_slice(&source[idx * 1900 + 500..idx * 1900 + 1500]);
+        }
+
+        let options = EncoderOptions::new()
+            .max_target_window_size(1000)
+            .source_segment_size(2000);
+        let delta = encode_with(&source, &target, 16, options);
+
+        let mut count = 0;
+        for window in windows(&delta) {
+            let window = window.unwrap();
+            let (_, segment_size) = window.header().source_segment.unwrap();
+            assert_eq!(window.header().target_window_size, 1000);
+            assert_eq!(segment_size, 2000);
+            count += 1;
+        }
+        assert_eq!(count, 10);
+        assert!(delta.len() < 200);
+    }
+
+    #[test]
+    fn target_matches() {
+        let text = read_file("tst/text-1/target.txt");
+        let mut target = text.clone();
+        target.extend_from_slice(&text);
+        let delta = encode(b"", &target);
+        assert!(delta.len() < text.len());
+        assert_eq!(decode(b"", &delta).unwrap(), target);
+
+        let window = windows(&delta).next().unwrap().unwrap();
+        assert_eq!(window.header().win_indicator, 0);
+        assert!(window.instructions().any(|op| matches!(
+            op,
+            Ok(Op::Copy {
+                from: CopyFrom::Target,
+                ..
+            })
+        )));
+    }
+
+    #[test]
+    fn target_windows() {
+        let text = read_file("tst/text-1/target.txt");
+        let options = EncoderOptions::new().max_target_window_size(text.len() as u32);
+        let mut target = text.clone();
+        target.extend_from_slice(&text);
+        let delta = encode_with(b"", &target, 16, options);
+        assert!(delta.len() < text.len());
+
+        let headers: Vec<_> = windows(&delta)
+            .map(|window| window.unwrap().header().clone())
+            .collect();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[0].win_indicator, 0);
+        assert_eq!(headers[1].win_indicator, VCD_TARGET);
+        assert_eq!(headers[1].source_segment, Some((0, text.len() as u64)));
+    }
+
+    #[test]
+    fn compression_levels() {
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        let level = |level| EncoderOptions::new().compression_level(level);
+
+        let fast = encode_with(&source, &target, 8, level(CompressionLevel::Fast));
+        let best = encode_with(&source, &target, 8, level(CompressionLevel::Best));
+        let default = encode_with(&source, &target, 8, level(CompressionLevel::Default));
+        assert!(best.len() <= fast.len());
+        assert!(best.len() <= default.len());
+
+        // the zeros of the source and of the target all have the same hash
+        let zeros = vec![0; 256 << 10];
+        let delta = encode_with(&zeros, &zeros, 8, level(CompressionLevel::Best));
+        assert!(delta.len() < 100);
+
+        // no match is long enough to be copied
+        let options = EncoderOptions::new().min_match_length(target.len() + 1);
+        let delta = encode_with(&source, &target, 8, options);
+        for window in windows(&delta) {
+            for op in window.unwrap().instructions() {
+                assert!(!matches!(op.unwrap(), Op::Copy { .. }));
+            }
+        }
+    }
+
+    #[test]
+    fn suffix_array() {
+        // the target takes unaligned parts of the source, in another order
+        let source = read_file("tst/text-1/src.txt");
+        let mut target = source[1001..3003].to_vec();
+        target.extend_from_slice(&source[7..1001]);
+        target.extend_from_within(3..500);
+        let finder = |match_finder| EncoderOptions::new().match_finder(match_finder);
+        let delta = encode_with(&source, &target, 32, finder(MatchFinder::SuffixArray));
+        let rolling_hash = encode_with(&source, &target, 32, finder(MatchFinder::RollingHash));
+        assert!(delta.len() <= rolling_hash.len());
+        // COPY, COPY, then COPY from the target window
+        let mut count = 0;
+        for window in windows(&delta) {
+            for op in window.unwrap().instructions() {
+                assert!(matches!(op.unwrap(), Op::Copy { .. }));
+                count += 1;
+            }
+        }
+        assert_eq!(count, 3);
+
+        let text = read_file("tst/text-1/target.txt");
+        let options = EncoderOptions::new()
+            .match_finder(MatchFinder::SuffixArray)
+            .max_target_window_size(text.len() as u32 / 2);
+        encode_with(b"", &text, 16, options);
+    }
+
+    #[test]
+    fn optimal_parsing() {
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        for &match_finder in &[MatchFinder::RollingHash, MatchFinder::SuffixArray] {
+            let parse = |optimal_parsing: bool, lazy_matching: bool| {
+                let options = EncoderOptions::new()
+                    .match_finder(match_finder)
+                    .lazy_matching(lazy_matching)
+                    .optimal_parsing(optimal_parsing);
+                encode_with(&source, &target, 8, options).len()
+            };
+            let optimal = parse(true, false);
+            assert!(optimal <= parse(false, false));
+            assert!(optimal <= parse(false, true));
+        }
+
+        // every position of the zeros matches the rest of them
+        let zeros = vec![0; 256 << 10];
+        for &match_finder in &[MatchFinder::RollingHash, MatchFinder::SuffixArray] {
+            for source in [&[][..], &zeros[..64 << 10]] {
+                let options = EncoderOptions::new()
+                    .match_finder(match_finder)
+                    .optimal_parsing(true);
+                let delta = encode_with(source, &zeros, 16, options);
+                assert!(delta.len() < 100);
+            }
+        }
+    }
+
+    #[test]
+    fn index_memory() {
+        assert_eq!(WindowHashMap::layout(0, 16, 0), (16, 0, 1));
+        assert_eq!(WindowHashMap::layout(1000, 16, 1 << 30), (16, 62, 64));
+        let (stride, indexes_size, hash_size) = WindowHashMap::layout(1 << 40, 16, 1 << 30);
+        assert_eq!(stride, 16 << 10);
+        assert_eq!(indexes_size, 1 << 26);
+        assert_eq!(hash_size, 1 << 23);
+
+        // every fourth block of the source is indexed
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        let options = EncoderOptions::new().max_index_memory(2048);
+        let encoder = VCDiffEncoder::with_options(
+            Cursor::new(&source),
+            Cursor::new(&target),
+            16,
+            options.clone(),
+        )
+        .unwrap();
+        assert_eq!(encoder.old_hash_map.stride, 64);
+        let delta = encode_with(&source, &target, 16, options);
+        assert!(delta.len() < target.len());
+    }
+
+    #[test]
+    fn one_shot() {
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        let delta = encode(&source, &target);
+        assert_eq!(decode(&source, &delta).unwrap(), target);
+        assert_eq!(decode(b"", &encode(b"", b"")).unwrap(), b"");
+    }
 }
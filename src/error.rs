@@ -0,0 +1,99 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Resource limits of `DecoderOptions`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Limit {
+    TargetWindowSize,
+    TargetSize,
+    DeltaSectionSize,
+    BufferedInput,
+    TargetHistory,
+}
+
+/// Errors returned while decoding a delta
+#[derive(Debug)]
+pub enum Error {
+    /// reading the source or writing the target failed
+    Io(io::Error),
+    /// the delta doesn't start with the VCDIFF magic bytes
+    InvalidMagic,
+    /// the delta header is malformed
+    InvalidHeader,
+    /// the custom code table of the header is malformed
+    InvalidCodeTable,
+    /// the header requires a secondary compressor that isn't registered
+    UnsupportedSecondaryCompressor(u8),
+    /// a window header is malformed
+    InvalidWindowHeader,
+    /// the Delta_Indicator of a window has unknown bits set
+    InvalidDeltaIndicator(u8),
+    /// the secondary compressor failed to decompress a section
+    SecondaryDecompression(io::Error),
+    /// an instruction is malformed or needs more data than its window provides
+    InvalidInstruction,
+    /// a COPY address is malformed or points outside of the data available to the window
+    InvalidCopyAddress,
+    /// the delta ended in the middle of the header or of a window
+    UnexpectedEof,
+    /// the Adler-32 checksum of a reconstructed window doesn't match its header
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// the delta goes over one of the limits of the decoder options
+    LimitExceeded(Limit),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "i/o error: {}", err),
+            Error::InvalidMagic => write!(f, "not a VCDIFF delta"),
+            Error::InvalidHeader => write!(f, "invalid delta header"),
+            Error::InvalidCodeTable => write!(f, "invalid custom code table"),
+            Error::UnsupportedSecondaryCompressor(id) => {
+                write!(f, "unsupported secondary compressor {}", id)
+            }
+            Error::InvalidWindowHeader => write!(f, "invalid window header"),
+            Error::InvalidDeltaIndicator(indicator) => {
+                write!(f, "invalid delta indicator {:#04x}", indicator)
+            }
+            Error::SecondaryDecompression(ref err) => {
+                write!(f, "secondary decompression failed: {}", err)
+            }
+            Error::InvalidInstruction => write!(f, "invalid instruction"),
+            Error::InvalidCopyAddress => write!(f, "invalid copy address"),
+            Error::UnexpectedEof => write!(f, "unexpected end of delta"),
+            Error::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "window checksum mismatch (expected {:08x}, got {:08x})",
+                expected, actual
+            ),
+            Error::LimitExceeded(limit) => write!(f, "decoder limit exceeded: {:?}", limit),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) | Error::SecondaryDecompression(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::UnexpectedEof => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::io;
+
+/// number of leaves of the tree, one per byte value
+static ALPHABET_SIZE: usize = 256;
+
+/// node of the tree. The leaves are the first `ALPHABET_SIZE` nodes, indexed by their
+/// byte, followed by the internal nodes in their allocation order.
+#[derive(Debug, Copy, Clone, Default)]
+struct Node {
+    weight: u32,
+    parent: Option<usize>,
+    /// for the leaves of weight 0, the previous node in the list of remaining zeros
+    left_child: Option<usize>,
+    /// for the leaves of weight 0, the next node in the list of remaining zeros
+    right_child: Option<usize>,
+    /// neighbours in the order of the nodes by weight
+    left: Option<usize>,
+    right: Option<usize>,
+    /// block of the nodes with the same weight
+    block: usize,
+}
+
+fn link(node: Option<usize>) -> usize {
+    node.expect("FGK tree links are set")
+}
+
+/// xdelta3's adaptive Huffman tree (Faller, Gallager and Knuth).
+///
+/// The bytes not seen yet share a single leaf of weight 0, the head of the list of
+/// remaining zeros: a byte reaching it is followed by its index in the list. The tree
+/// is updated after each byte, in the same way by the encoder and the decoder.
+struct FgkTree {
+    nodes: Vec<Node>,
+    root: usize,
+    remaining_zeros: Option<usize>,
+    /// number of bytes not seen yet, `2^zero_freq_exp + zero_freq_rem`
+    zero_freq_count: usize,
+    zero_freq_exp: usize,
+    zero_freq_rem: usize,
+    /// leader of each block, the rightmost node of its weight
+    blocks: Vec<usize>,
+    free_blocks: Vec<usize>,
+}
+
+impl FgkTree {
+    fn new() -> FgkTree {
+        let nodes = (0..ALPHABET_SIZE)
+            .map(|i| Node {
+                left_child: i.checked_sub(1),
+                right_child: Some(i + 1).filter(|&next| next < ALPHABET_SIZE),
+                ..Node::default()
+            })
+            .collect();
+        let mut tree = FgkTree {
+            nodes,
+            root: 0,
+            remaining_zeros: Some(0),
+            zero_freq_count: ALPHABET_SIZE + 2,
+            zero_freq_exp: 0,
+            zero_freq_rem: 0,
+            blocks: Vec::new(),
+            free_blocks: Vec::new(),
+        };
+        tree.factor_remaining();
+        tree.factor_remaining();
+        tree
+    }
+
+    fn factor_remaining(&mut self) {
+        self.zero_freq_count -= 1;
+        let mut i = self.zero_freq_count;
+        self.zero_freq_exp = 0;
+        while i > 1 {
+            self.zero_freq_exp += 1;
+            i >>= 1;
+        }
+        self.zero_freq_rem = self.zero_freq_count - (1 << self.zero_freq_exp);
+    }
+
+    /// number of bits of the index of a byte in the list of remaining zeros
+    fn zero_index_bits(&self) -> usize {
+        if self.zero_freq_rem == 0 {
+            self.zero_freq_exp
+        } else {
+            self.zero_freq_exp + 1
+        }
+    }
+
+    /// the `n`th byte of the list of remaining zeros, or its last one
+    fn nth_zero(&self, mut n: usize) -> usize {
+        let mut node = link(self.remaining_zeros);
+        while let (true, Some(next)) = (n != 0, self.nodes[node].right_child) {
+            node = next;
+            n -= 1;
+        }
+        node
+    }
+
+    fn make_block(&mut self, leader: usize) -> usize {
+        match self.free_blocks.pop() {
+            Some(block) => {
+                self.blocks[block] = leader;
+                block
+            }
+            None => {
+                self.blocks.push(leader);
+                self.blocks.len() - 1
+            }
+        }
+    }
+
+    fn free_block(&mut self, block: usize) {
+        self.free_blocks.push(block);
+    }
+
+    fn weight(&self, node: usize) -> u32 {
+        self.nodes[node].weight
+    }
+
+    /// counts an occurrence of `byte`
+    fn update(&mut self, byte: usize) {
+        let mut node = if self.weight(byte) == 0 {
+            self.increase_zero_weight(byte)
+        } else {
+            byte
+        };
+        while node != self.root {
+            self.move_right(node);
+            self.promote(node);
+            self.nodes[node].weight += 1;
+            node = link(self.nodes[node].parent);
+        }
+        self.nodes[self.root].weight += 1;
+    }
+
+    /// swaps `move_fwd` with the leader of its block, in the order and in the tree
+    fn move_right(&mut self, move_fwd: usize) {
+        if self.weight(move_fwd) == 0 {
+            return;
+        }
+        let move_back = self.blocks[self.nodes[move_fwd].block];
+        if move_fwd == move_back || self.nodes[move_fwd].parent == Some(move_back) {
+            return;
+        }
+
+        let back_right = link(self.nodes[move_back].right);
+        self.nodes[back_right].left = Some(move_fwd);
+        if let Some(fwd_left) = self.nodes[move_fwd].left {
+            self.nodes[fwd_left].right = Some(move_back);
+        }
+        let fwd_right = self.nodes[move_fwd].right;
+        self.nodes[move_fwd].right = self.nodes[move_back].right;
+        if fwd_right == Some(move_back) {
+            self.nodes[move_back].right = Some(move_fwd);
+        } else {
+            self.nodes[link(fwd_right)].left = Some(move_back);
+            self.nodes[move_back].right = fwd_right;
+        }
+        let back_left = self.nodes[move_back].left;
+        self.nodes[move_back].left = self.nodes[move_fwd].left;
+        if back_left == Some(move_fwd) {
+            self.nodes[move_fwd].left = Some(move_back);
+        } else {
+            self.nodes[link(back_left)].right = Some(move_fwd);
+            self.nodes[move_fwd].left = back_left;
+        }
+
+        let fwd_parent = link(self.nodes[move_fwd].parent);
+        let back_parent = link(self.nodes[move_back].parent);
+        let fwd_is_right = self.nodes[fwd_parent].right_child == Some(move_fwd);
+        let back_is_right = self.nodes[back_parent].right_child == Some(move_back);
+        if fwd_is_right {
+            self.nodes[fwd_parent].right_child = Some(move_back);
+        } else {
+            self.nodes[fwd_parent].left_child = Some(move_back);
+        }
+        if back_is_right {
+            self.nodes[back_parent].right_child = Some(move_fwd);
+        } else {
+            self.nodes[back_parent].left_child = Some(move_fwd);
+        }
+        self.nodes[move_fwd].parent = Some(back_parent);
+        self.nodes[move_back].parent = Some(fwd_parent);
+    }
+
+    /// moves `node`, about to be incremented, from its block to the block of the next
+    /// weight
+    fn promote(&mut self, node: usize) {
+        if self.weight(node) == 0 {
+            return;
+        }
+        let Node {
+            left: my_left,
+            right: my_right,
+            block: cur_block,
+            left_child,
+            right_child,
+            ..
+        } = self.nodes[node];
+        let joins_right = |tree: &FgkTree| {
+            let my_right = link(my_right);
+            tree.weight(node) + 1 == tree.weight(my_right) && my_right != tree.root
+        };
+
+        // the parent of the remaining zeros has the weight of its other child
+        if my_left == right_child && left_child.is_some_and(|child| self.weight(child) == 0) {
+            if joins_right(self) {
+                self.free_block(cur_block);
+                let block = self.nodes[link(my_right)].block;
+                self.nodes[node].block = block;
+                self.nodes[link(my_left)].block = block;
+            }
+            return;
+        }
+        if my_left == self.remaining_zeros {
+            return;
+        }
+
+        let my_left = link(my_left);
+        if self.nodes[my_left].block == cur_block {
+            self.blocks[cur_block] = my_left;
+        } else {
+            self.free_block(cur_block);
+        }
+        self.nodes[node].block = if joins_right(self) {
+            self.nodes[link(my_right)].block
+        } else {
+            self.make_block(node)
+        };
+    }
+
+    /// removes `byte`, seen for the first time, from the remaining zeros: it becomes the
+    /// right child of a new internal node taking the place of the zero leaf
+    fn increase_zero_weight(&mut self, byte: usize) -> usize {
+        if self.zero_freq_count == 1 {
+            self.nodes[byte].right_child = None;
+            let right = link(self.nodes[byte].right);
+            self.nodes[byte].block = if self.weight(right) == 1 {
+                self.nodes[right].block
+            } else {
+                self.make_block(byte)
+            };
+            self.remaining_zeros = None;
+            return byte;
+        }
+
+        let zero = link(self.remaining_zeros);
+        let internal = self.nodes.len();
+        self.nodes.push(Node {
+            parent: self.nodes[zero].parent,
+            right: self.nodes[zero].right,
+            right_child: Some(byte),
+            left: Some(byte),
+            ..Node::default()
+        });
+        if zero == self.root {
+            // the first byte of the section
+            self.root = internal;
+            self.nodes[byte].block = self.make_block(byte);
+            self.nodes[internal].block = self.make_block(internal);
+        } else {
+            let right = link(self.nodes[internal].right);
+            self.nodes[right].left = Some(internal);
+            let parent = link(self.nodes[zero].parent);
+            if self.nodes[parent].right_child == Some(zero) {
+                self.nodes[parent].right_child = Some(internal);
+            } else {
+                self.nodes[parent].left_child = Some(internal);
+            }
+            self.nodes[internal].block = if self.weight(right) == 1 {
+                self.nodes[right].block
+            } else {
+                self.make_block(internal)
+            };
+            self.nodes[byte].block = self.nodes[internal].block;
+        }
+
+        self.eliminate_zero(byte);
+        let zero = link(self.remaining_zeros);
+        self.nodes[internal].left_child = Some(zero);
+        self.nodes[byte] = Node {
+            right: Some(internal),
+            left: Some(zero),
+            parent: Some(internal),
+            left_child: None,
+            right_child: None,
+            ..self.nodes[byte]
+        };
+        self.nodes[zero].parent = Some(internal);
+        self.nodes[zero].right = Some(byte);
+        byte
+    }
+
+    /// unlinks `node` from the list of remaining zeros
+    fn eliminate_zero(&mut self, node: usize) {
+        if self.zero_freq_count == 1 {
+            return;
+        }
+        self.factor_remaining();
+        let Node {
+            left_child,
+            right_child,
+            ..
+        } = self.nodes[node];
+        match (left_child, right_child) {
+            (None, next) => {
+                self.remaining_zeros = next;
+                self.nodes[link(next)].left_child = None;
+            }
+            (Some(previous), None) => self.nodes[previous].right_child = None,
+            (Some(previous), Some(next)) => {
+                self.nodes[next].left_child = Some(previous);
+                self.nodes[previous].right_child = Some(next);
+            }
+        }
+    }
+
+    /// bits of `byte`, in the order they are written
+    #[cfg(test)]
+    fn encode(&mut self, byte: usize) -> Vec<bool> {
+        let mut bits = Vec::new();
+        let mut node = byte;
+        if self.weight(byte) == 0 {
+            let mut index = 0;
+            let mut zero = link(self.remaining_zeros);
+            while zero != byte {
+                zero = link(self.nodes[zero].right_child);
+                index += 1;
+            }
+            bits.extend((0..self.zero_index_bits()).map(|bit| index & (1 << bit) != 0));
+            node = link(self.remaining_zeros);
+        }
+        while node != self.root {
+            let parent = link(self.nodes[node].parent);
+            bits.push(self.nodes[parent].right_child == Some(node));
+            node = parent;
+        }
+        self.update(byte);
+        bits.reverse();
+        bits
+    }
+}
+
+/// reads the bits of a section, from the lowest bit of each byte
+struct BitReader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    mask: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn read(&mut self) -> Result<bool, io::Error> {
+        if self.mask == 0 {
+            if self.pos == self.input.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated FGK section",
+                ));
+            }
+            self.pos += 1;
+            self.mask = 1;
+        }
+        let bit = self.input[self.pos - 1] & self.mask != 0;
+        self.mask = self.mask.wrapping_shl(1);
+        Ok(bit)
+    }
+}
+
+/// decodes `decompressed_size` bytes of an xdelta3 FGK section
+pub fn decompress(input: &[u8], decompressed_size: usize) -> Result<Vec<u8>, io::Error> {
+    let mut tree = FgkTree::new();
+    let mut bits = BitReader {
+        input,
+        pos: 0,
+        mask: 0,
+    };
+    // every byte but the first takes a bit at least
+    let mut output = Vec::with_capacity(decompressed_size.min(input.len() * 8 + 1));
+    while output.len() < decompressed_size {
+        let mut node = tree.root;
+        let byte = loop {
+            if tree.weight(node) == 0 {
+                let mut index = 0;
+                if tree.zero_freq_count > 1 {
+                    for _ in 0..tree.zero_index_bits() {
+                        index = index << 1 | bits.read()? as usize;
+                    }
+                }
+                break tree.nth_zero(index);
+            }
+            if tree.nodes[node].left_child.is_none() {
+                break node;
+            }
+            node = link(if bits.read()? {
+                tree.nodes[node].right_child
+            } else {
+                tree.nodes[node].left_child
+            });
+        };
+        tree.update(byte);
+        output.push(byte as u8);
+    }
+    Ok(output)
+}
+
+/// encodes `data` as an xdelta3 FGK section, without its size
+#[cfg(test)]
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut tree = FgkTree::new();
+    let mut output = Vec::new();
+    let mut mask = 0u8;
+    for &byte in data {
+        for bit in tree.encode(usize::from(byte)) {
+            if mask == 0 {
+                output.push(0);
+                mask = 1;
+            }
+            if bit {
+                *output.last_mut().unwrap() |= mask;
+            }
+            mask = mask.wrapping_shl(1);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, decompress};
+
+    #[test]
+    fn first_byte() {
+        // the index of 'A' in the 256 remaining zeros, on 8 bits from the highest one
+        assert_eq!(compress(b"A"), [0x82]);
+        assert_eq!(decompress(&[0x82], 1).unwrap(), b"A");
+        assert!(decompress(&[0x82], 2).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut seed = 7u32;
+        let mut random = |max: u32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) % max
+        };
+        let text = crate::test_util::read_file("tst/text-1/target.txt");
+        let skewed: Vec<u8> = (0..20000)
+            .map(|_| {
+                let max = random(256) + 1;
+                random(max) as u8
+            })
+            .collect();
+        let all_bytes: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        for data in [&text[..], &skewed, &all_bytes, &[0; 1000], b"", b"ab"] {
+            let compressed = compress(data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+        }
+        assert!(compress(&text).len() < text.len() * 3 / 4);
+    }
+}
@@ -0,0 +1,385 @@
+use crate::address_cache::AddressCache;
+use crate::code_table::{CodeTable, Instruction, InstructionType};
+use crate::decoder::{DecoderOptions, DecoderState};
+use crate::decoder_core::{DecoderCore, Sections, Step};
+use crate::error::Error;
+use crate::secondary::SecondaryCompressor;
+use crate::varint::VarIntDecode;
+use crate::vcdiff::WindowHeader;
+use nom::IResult;
+use std::rc::Rc;
+
+/// where the bytes of a COPY instruction come from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CopyFrom {
+    /// the source segment of the window, in the source file for VCD_SOURCE windows
+    /// or in the target for VCD_TARGET windows.
+    ///
+    /// the copy continues at the start of the target window when it goes past the
+    /// end of the segment.
+    Source,
+    /// the target window itself
+    Target,
+}
+
+/// instruction of a window, with its size and COPY address resolved
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op<'a> {
+    Add(&'a [u8]),
+    Run(u8, usize),
+    /// `addr` is the offset of the copied bytes in the source segment or in the target window
+    Copy {
+        addr: u64,
+        len: usize,
+        from: CopyFrom,
+    },
+}
+
+/// decodes the instructions section of a window, taking the added data and the
+/// COPY addresses from the two other sections, or from the instructions section
+/// itself for the interleaved windows of open-vcdiff.
+///
+/// decoding stops at the first error.
+pub struct Instructions<'a> {
+    code_table: &'a CodeTable,
+    address_cache: AddressCache,
+    source_length: u64,
+    target_window_size: usize,
+    /// size of the target window produced by the instructions decoded so far
+    here: usize,
+    adds_runs: &'a [u8],
+    instructions: &'a [u8],
+    copy_addresses: &'a [u8],
+    interleaved: bool,
+    /// second instruction of a double opcode
+    pending: Option<Instruction>,
+    done: bool,
+}
+
+impl<'a> Instructions<'a> {
+    pub(crate) fn new(
+        code_table: &'a CodeTable,
+        window_header: &WindowHeader,
+        adds_runs: &'a [u8],
+        instructions: &'a [u8],
+        copy_addresses: &'a [u8],
+        interleaved: bool,
+    ) -> Instructions<'a> {
+        Instructions {
+            code_table,
+            address_cache: AddressCache::new(
+                code_table.near_cache_size as usize,
+                code_table.same_cache_size as usize,
+            ),
+            source_length: window_header.source_segment.map_or(0, |s| s.1),
+            target_window_size: window_header.target_window_size as usize,
+            here: 0,
+            adds_runs,
+            instructions,
+            copy_addresses,
+            interleaved,
+            pending: None,
+            done: false,
+        }
+    }
+
+    /// section holding the data of ADD and RUN instructions
+    fn adds_runs(&mut self) -> &mut &'a [u8] {
+        if self.interleaved {
+            &mut self.instructions
+        } else {
+            &mut self.adds_runs
+        }
+    }
+
+    /// section holding the addresses of COPY instructions
+    fn copy_addresses(&mut self) -> &mut &'a [u8] {
+        if self.interleaved {
+            &mut self.instructions
+        } else {
+            &mut self.copy_addresses
+        }
+    }
+
+    fn decode(&mut self, inst: Instruction) -> Result<Op<'a>, Error> {
+        let mut size = inst.size as usize;
+        if size == 0 {
+            match usize::decode_varint(self.instructions) {
+                IResult::Done(r, sz) => {
+                    self.instructions = r;
+                    size = sz;
+                }
+                _ => Err(Error::InvalidInstruction)?,
+            };
+        }
+        if size > self.target_window_size - self.here {
+            Err(Error::InvalidInstruction)?;
+        }
+
+        let op = match inst.typ {
+            InstructionType::Add => {
+                let adds_runs = self.adds_runs();
+                if size > adds_runs.len() {
+                    Err(Error::InvalidInstruction)?;
+                }
+                let (data, r) = adds_runs.split_at(size);
+                *adds_runs = r;
+                Op::Add(data)
+            }
+            InstructionType::Run => {
+                let adds_runs = self.adds_runs();
+                let (&byte, r) = adds_runs.split_first().ok_or(Error::InvalidInstruction)?;
+                *adds_runs = r;
+                Op::Run(byte, size)
+            }
+            InstructionType::Copy => {
+                let here = self
+                    .source_length
+                    .checked_add(self.here as u64)
+                    .ok_or(Error::InvalidCopyAddress)?;
+                let copy_addresses = *self.copy_addresses();
+                let (r, addr) = self.address_cache.decode(here, inst.mode, copy_addresses)?;
+                *self.copy_addresses() = r;
+                if addr < self.source_length {
+                    Op::Copy {
+                        addr,
+                        len: size,
+                        from: CopyFrom::Source,
+                    }
+                } else {
+                    Op::Copy {
+                        addr: addr - self.source_length,
+                        len: size,
+                        from: CopyFrom::Target,
+                    }
+                }
+            }
+        };
+        self.here += size;
+        Ok(op)
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Op<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Op<'a>, Error>> {
+        if self.done {
+            return None;
+        }
+        let inst = match self.pending.take() {
+            Some(inst) => inst,
+            None => match self.instructions.split_first() {
+                Some((&opcode, r)) => {
+                    self.instructions = r;
+                    let (first, second) = match self.code_table.entries[opcode as usize] {
+                        Some(entry) => entry,
+                        None => {
+                            self.done = true;
+                            return Some(Err(Error::InvalidInstruction));
+                        }
+                    };
+                    self.pending = second;
+                    first
+                }
+                None => {
+                    self.done = true;
+                    if self.here != self.target_window_size {
+                        return Some(Err(Error::InvalidInstruction));
+                    }
+                    return None;
+                }
+            },
+        };
+        let op = self.decode(inst);
+        self.done = op.is_err();
+        Some(op)
+    }
+}
+
+/// window of a delta, see `windows`
+pub struct Window<'a> {
+    header: WindowHeader,
+    code_table: Rc<CodeTable>,
+    sections: Sections<'a>,
+}
+
+impl<'a> Window<'a> {
+    pub fn header(&self) -> &WindowHeader {
+        &self.header
+    }
+
+    /// the instructions building the target window
+    pub fn instructions(&self) -> Instructions<'_> {
+        self.sections.instructions(&self.code_table, &self.header)
+    }
+}
+
+/// iterator over the windows of a delta, see `windows`
+pub struct Windows<'a> {
+    core: DecoderCore,
+    code_table: Option<Rc<CodeTable>>,
+    remaining: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Windows<'a> {
+    /// registers a secondary compressor, replacing any known compressor with the same id
+    pub fn add_secondary_compressor(&mut self, compressor: Box<dyn SecondaryCompressor>) {
+        self.core.add_secondary_compressor(compressor);
+    }
+
+    /// application defined header of the delta (VCD_APPHEADER), available once the header is decoded
+    pub fn app_header(&self) -> Option<&[u8]> {
+        self.core.app_header()
+    }
+
+    /// id of the secondary compressor of the delta (VCD_DECOMPRESS), available once the
+    /// header is decoded
+    pub fn secondary_compressor_id(&self) -> Option<u8> {
+        self.core.secondary_compressor_id()
+    }
+
+    /// version byte of the delta header, 0x53 ('S') for the open-vcdiff format extensions,
+    /// available once the header is decoded
+    pub fn version(&self) -> u8 {
+        self.core.version()
+    }
+
+    /// whether the delta has its own code table (VCD_CODETABLE), available once the header
+    /// is decoded
+    pub fn has_custom_code_table(&self) -> bool {
+        self.core.has_custom_code_table()
+    }
+
+    /// code table of the delta, the default one or the custom one once the header is
+    /// decoded
+    pub fn code_table(&self) -> &CodeTable {
+        self.core.code_table()
+    }
+
+    fn next_window(&mut self) -> Result<Option<Window<'a>>, Error> {
+        while let Some((remaining, step)) = self.core.step(self.remaining)? {
+            self.remaining = remaining;
+            match step {
+                Step::Header => self.code_table = Some(Rc::new(self.core.code_table().clone())),
+                Step::WindowHeader => {}
+                Step::Window(window) => {
+                    let header = self.core.window_header().clone();
+                    let sections = self.core.sections(window)?;
+                    // the window isn't decoded, only its size is accounted for
+                    self.core.skip_window();
+                    return Ok(Some(Window {
+                        header,
+                        code_table: self.code_table.clone().expect("header before windows"),
+                        sections,
+                    }));
+                }
+            }
+        }
+        if self.core.input_state(self.remaining.len()) != DecoderState::WantMoreInputOrDone {
+            Err(Error::UnexpectedEof)?;
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Iterator for Windows<'a> {
+    type Item = Result<Window<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Window<'a>, Error>> {
+        if self.done {
+            return None;
+        }
+        let window = self.next_window();
+        self.done = !matches!(window, Ok(Some(_)));
+        window.transpose()
+    }
+}
+
+/// parses the windows of `delta`, without needing its source
+pub fn windows(delta: &[u8]) -> Windows<'_> {
+    Windows {
+        core: DecoderCore::new(0, DecoderOptions::default()),
+        code_table: None,
+        remaining: delta,
+        done: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{windows, CopyFrom, Op};
+    use crate::test_util::read_file;
+    use crate::{decode, Error};
+
+    #[test]
+    fn text_1() {
+        let src = read_file("tst/text-1/src.txt");
+        let patch = read_file("tst/text-1/l.patch");
+        let target = read_file("tst/text-1/target.txt");
+
+        // rebuilds the target from the instructions
+        let mut rebuilt = Vec::new();
+        let mut windows = windows(&patch);
+        for window in &mut windows {
+            let window = window.unwrap();
+            let (segment_pos, _) = window.header().source_segment.unwrap();
+            let start = rebuilt.len();
+            for op in window.instructions() {
+                match op.unwrap() {
+                    Op::Add(bytes) => rebuilt.extend_from_slice(bytes),
+                    Op::Run(byte, len) => rebuilt.resize(rebuilt.len() + len, byte),
+                    Op::Copy { addr, len, from } => {
+                        let pos = match from {
+                            CopyFrom::Source => (segment_pos + addr) as usize,
+                            CopyFrom::Target => start + addr as usize,
+                        };
+                        for idx in 0..len {
+                            let byte = match from {
+                                CopyFrom::Source => src[pos + idx],
+                                CopyFrom::Target => rebuilt[pos + idx],
+                            };
+                            rebuilt.push(byte);
+                        }
+                    }
+                }
+            }
+            assert_eq!(
+                rebuilt.len() - start,
+                window.header().target_window_size as usize
+            );
+        }
+        assert!(windows.app_header().unwrap().ends_with(b"\\LICENSE/"));
+        assert_eq!(rebuilt, target);
+        assert_eq!(decode(&src, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn truncated() {
+        let patch = read_file("tst/text-1/l.patch");
+        let res: Result<Vec<_>, Error> = windows(&patch[..patch.len() - 1]).collect();
+        match res {
+            Err(Error::UnexpectedEof) => {}
+            _ => panic!("truncated delta accepted"),
+        }
+    }
+
+    #[test]
+    fn huge_source_segment() {
+        // a u64::MAX long source segment, then ADD 1 and COPY 1
+        let mut delta = b"\xD6\xC3\xC4\x00\x00\x01\x81".to_vec();
+        delta.extend_from_slice(&[0xFF; 8]);
+        delta.extend_from_slice(b"\x7F\x00\x0A\x02\x00\x01\x03\x01x\x02\x13\x01\x00");
+        let window = windows(&delta).next().unwrap().unwrap();
+        let res: Result<Vec<_>, Error> = window.instructions().collect();
+        match res {
+            Err(Error::InvalidCopyAddress) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        match decode(b"", &delta) {
+            Err(Error::InvalidCopyAddress) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
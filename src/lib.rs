@@ -1,15 +1,58 @@
+#[cfg(feature = "lzma")]
+extern crate lzma_rs;
 #[macro_use]
 extern crate nom;
 
 mod address_cache;
+mod adler32;
 mod code_table;
 mod decoder;
+mod decoder_core;
+mod error;
+mod fgk;
+mod instructions;
+mod reader;
+mod secondary;
 mod varint;
 mod vcdiff;
+mod writer;
 
-#[cfg(encoder)]
+#[cfg(feature = "tokio")]
+mod async_decoder;
+#[cfg(feature = "encoder")]
 mod encoder;
-#[cfg(encoder)]
+#[cfg(feature = "encoder")]
+mod optimal_parser;
+#[cfg(feature = "encoder")]
 mod rolling_hash;
+#[cfg(feature = "encoder")]
+mod suffix_array;
+#[cfg(feature = "encoder")]
+mod table_optimizer;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "encoder")]
+mod window_writer;
 
-pub use decoder::{DecoderState, ReadSlice, VCDiffDecoder};
+#[cfg(feature = "tokio")]
+pub use crate::async_decoder::AsyncVCDiffDecoder;
+pub use crate::code_table::{CodeTable, CodeTableBuilder, Instruction, InstructionType};
+pub use crate::decoder::{decode, DecoderOptions, DecoderState, VCDiffDecoder};
+#[cfg(feature = "encoder")]
+pub use crate::encoder::{encode, CompressionLevel, EncoderOptions, MatchFinder, VCDiffEncoder};
+pub use crate::error::{Error, Limit};
+pub use crate::instructions::{windows, CopyFrom, Instructions, Op, Window, Windows};
+pub use crate::reader::VCDiffReader;
+#[cfg(feature = "lzma")]
+pub use crate::secondary::Lzma;
+pub use crate::secondary::{Fgk, SecondaryCompressor};
+#[cfg(feature = "encoder")]
+pub use crate::table_optimizer::InstructionStats;
+pub use crate::vcdiff::{ChecksumDialect, WindowHeader};
+pub use crate::writer::VCDiffWriter;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing {
+    pub use crate::vcdiff::{header, window_header};
+}
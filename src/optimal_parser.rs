@@ -0,0 +1,420 @@
+use crate::code_table::{InstructionType, OpcodeMap};
+use crate::encoder::Match;
+use crate::varint::VarIntEncode;
+use crate::window_writer::MIN_RUN_LENGTH;
+use std::cmp;
+use std::io;
+
+/// matches at least this long are taken as soon as they are found
+pub(crate) static NICE_MATCH_LENGTH: usize = 256;
+
+/// longest stretch of the target window parsed at once, only the matches of a segment
+/// are kept in memory
+static MAX_SEGMENT_LENGTH: usize = 4096;
+
+/// longest COPY size of the default code table opcodes, the shorter matches are tried at
+/// every size up to it
+static MAX_OPCODE_COPY_SIZE: usize = 18;
+
+type PendingInstruction = (InstructionType, usize, u8);
+
+/// size of the instructions written so far by a `WindowWriter`
+#[derive(Debug, Copy, Clone, Default)]
+struct ParseState {
+    /// bytes written, data and addresses included
+    cost: u64,
+    /// instruction not written yet, as it may be merged with the next one
+    pending: Option<PendingInstruction>,
+    /// unmatched bytes before `run_len`, not written yet
+    literal: usize,
+    /// last unmatched bytes, all equal to `run_byte`
+    run_len: usize,
+    run_byte: u8,
+}
+
+impl ParseState {
+    fn single_cost(opcodes: &OpcodeMap, (typ, size, mode): PendingInstruction) -> u64 {
+        let (_, explicit) = opcodes
+            .single(typ, size, mode)
+            .expect("code table must have an explicit size opcode for every instruction");
+        1 + if explicit {
+            size.encode_varint().count() as u64
+        } else {
+            0
+        }
+    }
+
+    fn push(&mut self, opcodes: &OpcodeMap, inst: PendingInstruction) {
+        if let Some(pending) = self.pending.take() {
+            if let Some((_, explicit_first, explicit_second)) = opcodes.double(pending, inst) {
+                self.cost += 1;
+                if explicit_first {
+                    self.cost += pending.1.encode_varint().count() as u64;
+                }
+                if explicit_second {
+                    self.cost += inst.1.encode_varint().count() as u64;
+                }
+                return;
+            }
+            self.cost += ParseState::single_cost(opcodes, pending);
+        }
+        self.pending = Some(inst);
+    }
+
+    /// writes the unmatched bytes before the run, as `WindowWriter::add` does
+    fn push_literal(&mut self, opcodes: &OpcodeMap) {
+        if self.literal > 0 {
+            self.cost += self.literal as u64;
+            self.push(opcodes, (InstructionType::Add, self.literal, 0));
+            self.literal = 0;
+        }
+    }
+
+    /// ends the run of the last unmatched bytes, with a RUN if it is long enough
+    fn end_run(&mut self, opcodes: &OpcodeMap) {
+        if self.run_len >= MIN_RUN_LENGTH {
+            self.push_literal(opcodes);
+            self.cost += 1;
+            self.push(opcodes, (InstructionType::Run, self.run_len, 0));
+        } else {
+            self.literal += self.run_len;
+        }
+        self.run_len = 0;
+    }
+
+    /// adds `len` unmatched bytes equal to `byte`
+    fn add_run(&mut self, opcodes: &OpcodeMap, byte: u8, len: usize) {
+        if self.run_len > 0 && byte == self.run_byte {
+            self.run_len += len;
+            return;
+        }
+        self.end_run(opcodes);
+        self.run_byte = byte;
+        self.run_len = len;
+    }
+
+    /// writes the unmatched bytes
+    fn end_unmatched(&mut self, opcodes: &OpcodeMap) {
+        self.end_run(opcodes);
+        self.push_literal(opcodes);
+    }
+
+    /// copies `len` bytes from the address encoded as `value` with `mode`
+    fn copy(&mut self, opcodes: &OpcodeMap, same_mode: bool, (value, mode): (u64, u8), len: usize) {
+        self.end_unmatched(opcodes);
+        self.cost += if same_mode {
+            1
+        } else {
+            value.encode_varint().count() as u64
+        };
+        self.push(opcodes, (InstructionType::Copy, len, mode));
+    }
+
+    /// size of the window sections if it ended here
+    fn total_cost(&self, opcodes: &OpcodeMap) -> u64 {
+        let mut state = *self;
+        state.end_unmatched(opcodes);
+        state.cost
+            + state
+                .pending
+                .map_or(0, |pending| ParseState::single_cost(opcodes, pending))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    state: ParseState,
+    total_cost: u64,
+    /// position and kind of the previous node
+    from: (usize, usize),
+    /// COPY from the previous node, `None` for an unmatched byte
+    copy: Option<Match>,
+    /// position of the last node of the path reached by a COPY, in the segment
+    last_copy: Option<usize>,
+}
+
+/// index of the nodes reached by an unmatched byte, at each position
+static UNMATCHED: usize = 0;
+
+/// index of the nodes reached by a COPY, at each position
+static COPIED: usize = 1;
+
+/// the node of `nodes` with the smallest cost
+fn best_node(nodes: &[Option<Node>; 2]) -> Node {
+    nodes
+        .iter()
+        .flatten()
+        .min_by_key(|node| node.total_cost)
+        .cloned()
+        .expect("positions are reachable one by one")
+}
+
+/// addresses of the copies of the path to `node`, since the segment at `segment_start`
+fn path_copies(
+    nodes: &[[Option<Node>; 2]],
+    segment_start: usize,
+    node: &Node,
+    copies: &mut Vec<u64>,
+) {
+    copies.clear();
+    let mut last_copy = node.last_copy;
+    while let Some(copy_pos) = last_copy {
+        let copy_node = nodes[copy_pos - segment_start][COPIED].expect("path node");
+        copies.push(copy_node.copy.expect("reached by a COPY").addr);
+        let (from_pos, from_kind) = copy_node.from;
+        last_copy = nodes[from_pos - segment_start][from_kind]
+            .expect("path node")
+            .last_copy;
+    }
+    copies.reverse();
+}
+
+/// keeps the path to `next` through `from` when it is the smallest of its kind
+fn relax(
+    nodes: &mut [[Option<Node>; 2]],
+    segment_start: usize,
+    opcodes: &OpcodeMap,
+    (from, from_node): ((usize, usize), &Node),
+    next: usize,
+    state: ParseState,
+    copy: Option<Match>,
+) {
+    let kind = if copy.is_some() { COPIED } else { UNMATCHED };
+    let total_cost = state.total_cost(opcodes);
+    let slot = &mut nodes[next - segment_start][kind];
+    if slot.is_none_or(|n| total_cost < n.total_cost) {
+        *slot = Some(Node {
+            state,
+            total_cost,
+            from,
+            copy,
+            last_copy: copy.map(|_| next).or(from_node.last_copy),
+        });
+    }
+}
+
+/// chooses the COPY instructions of the target window at `start` of `data`, minimizing
+/// the size of its sections.
+///
+/// `find_match(pos, min_pos)` returns the longest match at a position of the target
+/// window, extended backward down to `min_pos`. It is called with increasing positions.
+/// The parse is the shortest path through the positions, pricing the opcodes, sizes,
+/// addresses and RUN instructions as written by `WindowWriter`: the address caches of
+/// each path are followed along it. Each position keeps its best path ending with a COPY
+/// and its best path ending with unmatched bytes. The window is parsed by segments of
+/// `MAX_SEGMENT_LENGTH` bytes, and the matches of `NICE_MATCH_LENGTH` bytes are taken as
+/// they are found.
+pub fn optimal_parse<F>(
+    opcodes: &OpcodeMap,
+    data: &[u8],
+    source_length: u64,
+    start: usize,
+    min_match_length: usize,
+    mut find_match: F,
+) -> Result<Vec<Match>, io::Error>
+where
+    F: FnMut(usize, usize) -> Result<Option<Match>, io::Error>,
+{
+    let here = |pos: usize| source_length + (pos - start) as u64;
+    let mut copies: Vec<Match> = Vec::new();
+    let mut address_cache = opcodes.address_cache();
+    let mut state = ParseState::default();
+    let mut segment_start = start;
+    let mut nodes: Vec<[Option<Node>; 2]> = Vec::new();
+    let mut addresses = Vec::new();
+    while segment_start < data.len() {
+        nodes.clear();
+        nodes.resize(MAX_SEGMENT_LENGTH + NICE_MATCH_LENGTH, [None; 2]);
+        nodes[0][UNMATCHED] = Some(Node {
+            state,
+            total_cost: state.total_cost(opcodes),
+            from: (segment_start, UNMATCHED),
+            copy: None,
+            last_copy: None,
+        });
+        let mut pos = segment_start;
+        let mut nice_match = None;
+        // end of the bytes equal to the one at `pos`
+        let mut run_end = pos;
+        while pos < data.len() && pos - segment_start < MAX_SEGMENT_LENGTH {
+            // the matches are extended backward in the segment, a long match is found
+            // again at the next positions anyway
+            let min_pos = cmp::max(pos.saturating_sub(NICE_MATCH_LENGTH), segment_start);
+            let found = find_match(pos, min_pos)?.filter(|m| m.len >= min_match_length);
+            if let Some(m) = found.filter(|m| m.len >= NICE_MATCH_LENGTH) {
+                nice_match = Some(m);
+                pos = m.target_pos;
+                break;
+            }
+
+            if run_end <= pos {
+                run_end = pos + data[pos..].iter().take_while(|&&b| b == data[pos]).count();
+            }
+            // the bytes of a run are reached at once, the shorter runs are added to the
+            // literals and would be dropped for a COPY before growing into a RUN
+            let run_len = cmp::min(run_end, segment_start + nodes.len() - 1) - pos;
+            for kind in [UNMATCHED, COPIED] {
+                let node = match nodes[pos - segment_start][kind] {
+                    Some(node) => node,
+                    None => continue,
+                };
+                let mut next_state = node.state;
+                next_state.add_run(opcodes, data[pos], 1);
+                let from = ((pos, kind), &node);
+                relax(
+                    &mut nodes,
+                    segment_start,
+                    opcodes,
+                    from,
+                    pos + 1,
+                    next_state,
+                    None,
+                );
+                if run_len >= MIN_RUN_LENGTH {
+                    let mut next_state = node.state;
+                    next_state.add_run(opcodes, data[pos], run_len);
+                    let next = pos + run_len;
+                    relax(
+                        &mut nodes,
+                        segment_start,
+                        opcodes,
+                        from,
+                        next,
+                        next_state,
+                        None,
+                    );
+                }
+            }
+
+            let m = match found {
+                Some(m) => m,
+                None => {
+                    pos += 1;
+                    continue;
+                }
+            };
+            // the match from its start, and from `pos` when it was extended backward,
+            // copying up to after `pos`
+            let mut copy_starts = vec![m.target_pos];
+            if m.target_pos < pos {
+                copy_starts.push(pos);
+            }
+            for copy_pos in copy_starts {
+                let skipped = copy_pos - m.target_pos;
+                let m = Match {
+                    addr: m.addr + skipped as u64,
+                    target_pos: copy_pos,
+                    len: m.len - skipped,
+                };
+                let first = cmp::max(cmp::max(min_match_length, 1), pos - copy_pos + 1);
+                let shortest = cmp::min(m.len, pos - copy_pos + MAX_OPCODE_COPY_SIZE);
+                for kind in [UNMATCHED, COPIED] {
+                    let node = match nodes[copy_pos - segment_start][kind] {
+                        Some(node) => node,
+                        None => continue,
+                    };
+                    // address of the match, with the caches of the path to the node
+                    path_copies(&nodes, segment_start, &node, &mut addresses);
+                    let address = address_cache.find_after(m.addr, here(copy_pos), &addresses);
+                    let same_mode = address_cache.is_same_mode(address.1);
+                    let from = ((copy_pos, kind), &node);
+                    for len in (first..=shortest).chain(Some(m.len).filter(|&len| len > shortest)) {
+                        let mut next_state = node.state;
+                        next_state.copy(opcodes, same_mode, address, len);
+                        let copy = Some(Match { len, ..m });
+                        let next = copy_pos + len;
+                        relax(
+                            &mut nodes,
+                            segment_start,
+                            opcodes,
+                            from,
+                            next,
+                            next_state,
+                            copy,
+                        );
+                    }
+                }
+            }
+            pos += 1;
+        }
+
+        // the best path to `pos`, backward
+        let mut path = Vec::new();
+        let mut node = best_node(&nodes[pos - segment_start]);
+        state = node.state;
+        let mut node_pos = pos;
+        while node_pos > segment_start {
+            path.extend(node.copy);
+            let (from_pos, from_kind) = node.from;
+            node_pos = from_pos;
+            node = nodes[node_pos - segment_start][from_kind].expect("path node");
+        }
+        for copy in path.into_iter().rev() {
+            address_cache.update(copy.addr);
+            copies.push(copy);
+        }
+
+        segment_start = pos;
+        if let Some(m) = nice_match {
+            let address = address_cache.encode(m.addr, here(pos));
+            state.copy(
+                opcodes,
+                address_cache.is_same_mode(address.1),
+                address,
+                m.len,
+            );
+            copies.push(m);
+            segment_start += m.len;
+        }
+    }
+    Ok(copies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimal_parse;
+    use crate::code_table::{CodeTable, OpcodeMap};
+    use crate::encoder::Match;
+
+    #[test]
+    fn longer_overlapping_match() {
+        let opcodes = OpcodeMap::new(&CodeTable::default());
+        let data: Vec<u8> = (0..21u8).collect();
+        // a greedy parse copies 5 bytes then adds 15 bytes
+        let copies = optimal_parse(&opcodes, &data, 100, 0, 4, |pos, _| {
+            Ok(match pos {
+                0 => Some(Match {
+                    addr: 10,
+                    target_pos: 0,
+                    len: 5,
+                }),
+                1 => Some(Match {
+                    addr: 50,
+                    target_pos: 1,
+                    len: 20,
+                }),
+                _ => None,
+            })
+        })
+        .unwrap();
+        assert_eq!(copies.len(), 1);
+        assert_eq!((copies[0].addr, copies[0].target_pos), (50, 1));
+        assert_eq!(copies[0].len, 20);
+    }
+
+    #[test]
+    fn runs() {
+        let opcodes = OpcodeMap::new(&CodeTable::default());
+        // a RUN of 64 bytes is smaller than a COPY with a 3 bytes address
+        let data = vec![7u8; 64];
+        let copies = optimal_parse(&opcodes, &data, 100_000, 0, 4, |pos, _| {
+            Ok(Some(Match {
+                addr: 50_000 + pos as u64,
+                target_pos: pos,
+                len: data.len() - pos,
+            }))
+        })
+        .unwrap();
+        assert!(copies.is_empty());
+    }
+}
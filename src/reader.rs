@@ -0,0 +1,136 @@
+use crate::decoder::{DecoderOptions, DecoderState, VCDiffDecoder};
+use std::cmp;
+use std::io;
+use std::io::{Read, Seek, Write};
+
+/// target of the decoder, holding the decoded bytes until they are read
+struct OutputBuffer {
+    data: Vec<u8>,
+    read_pos: usize,
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads the target of a delta, decoding the delta lazily as the target is read.
+pub struct VCDiffReader<R: Read, S: Read + Seek> {
+    delta: R,
+    decoder: VCDiffDecoder<S, OutputBuffer>,
+    chunk: Vec<u8>,
+    state: DecoderState,
+    done: bool,
+}
+
+impl<R: Read, S: Read + Seek> VCDiffReader<R, S> {
+    /// reads the target of `delta`, applied to `source`
+    pub fn new(delta: R, source: S) -> VCDiffReader<R, S> {
+        VCDiffReader::with_options(delta, source, DecoderOptions::default())
+    }
+
+    pub fn with_options(delta: R, source: S, options: DecoderOptions) -> VCDiffReader<R, S> {
+        let output = OutputBuffer {
+            data: Vec::new(),
+            read_pos: 0,
+        };
+        VCDiffReader {
+            delta,
+            decoder: VCDiffDecoder::with_options(source, output, 0, options),
+            chunk: vec![0u8; 8192],
+            state: DecoderState::WantMoreInput,
+            done: false,
+        }
+    }
+
+    /// application defined header of the delta (VCD_APPHEADER), available once the header is decoded
+    pub fn app_header(&self) -> Option<&[u8]> {
+        self.decoder.app_header()
+    }
+
+    pub fn into_inner(self) -> (R, S) {
+        (self.delta, self.decoder.into_inner().0)
+    }
+}
+
+impl<R: Read, S: Read + Seek> Read for VCDiffReader<R, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let output = self.decoder.get_mut().1;
+                let available = output.data.len() - output.read_pos;
+                if available > 0 || self.done || buf.is_empty() {
+                    let len = cmp::min(available, buf.len());
+                    buf[..len]
+                        .copy_from_slice(&output.data[output.read_pos..output.read_pos + len]);
+                    output.read_pos += len;
+                    if output.read_pos == output.data.len() {
+                        output.data.clear();
+                        output.read_pos = 0;
+                    }
+                    return Ok(len);
+                }
+            }
+
+            let read = match self.delta.read(&mut self.chunk) {
+                Ok(read) => read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            if read == 0 {
+                if self.state != DecoderState::WantMoreInputOrDone {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "delta ended in the middle of a window",
+                    ));
+                }
+                self.done = true;
+            } else {
+                self.state = self.decoder.decode(&self.chunk[..read])?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::read_file;
+    use crate::VCDiffReader;
+    use std::fs::File;
+    use std::io;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn text_1() {
+        let target = read_file("tst/text-1/target.txt");
+        let src = File::open("tst/text-1/src.txt").unwrap();
+        let patch = File::open("tst/text-1/l.patch").unwrap();
+        let mut reader = VCDiffReader::new(patch, src);
+        let mut decoded = Vec::new();
+        let mut buf = [0u8; 100];
+        loop {
+            let read = reader.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            decoded.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(decoded, target);
+        assert!(reader.app_header().unwrap().ends_with(b"\\LICENSE/"));
+    }
+
+    #[test]
+    fn truncated() {
+        let src = read_file("tst/text-1/src.txt");
+        let patch = read_file("tst/text-1/l.patch");
+        let mut reader = VCDiffReader::new(&patch[..patch.len() - 1], Cursor::new(src));
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::fgk;
+use std::io;
+
+/// xdelta3 identifier of its FGK secondary compressor
+pub static VCD_FGK_ID: u8 = 16;
+
+/// xdelta3 identifier of its LZMA secondary compressor
+#[cfg(feature = "lzma")]
+pub static VCD_LZMA_ID: u8 = 2;
+
+/// Decompressor for the delta sections of windows using secondary compression.
+///
+/// The header of a delta names the compressor by its id (VCD_DECOMPRESS), each
+/// window then flags its compressed sections in its Delta_Indicator.
+/// Compressed sections start with their decompressed size, followed by the
+/// compressor specific data.
+pub trait SecondaryCompressor: Send + Sync {
+    /// identifier of the compressor in the delta header
+    fn id(&self) -> u8;
+
+    /// decompresses the compressor specific data of a section into `decompressed_size` bytes
+    fn decompress(&self, input: &[u8], decompressed_size: usize) -> Result<Vec<u8>, io::Error>;
+}
+
+/// xdelta3 FGK secondary compressor, sections are coded with an adaptive Huffman tree
+pub struct Fgk;
+
+impl SecondaryCompressor for Fgk {
+    fn id(&self) -> u8 {
+        VCD_FGK_ID
+    }
+
+    fn decompress(&self, input: &[u8], decompressed_size: usize) -> Result<Vec<u8>, io::Error> {
+        fgk::decompress(input, decompressed_size)
+    }
+}
+
+/// xdelta3 LZMA secondary compressor, sections are stored as XZ streams
+#[cfg(feature = "lzma")]
+pub struct Lzma;
+
+#[cfg(feature = "lzma")]
+impl SecondaryCompressor for Lzma {
+    fn id(&self) -> u8 {
+        VCD_LZMA_ID
+    }
+
+    fn decompress(&self, input: &[u8], decompressed_size: usize) -> Result<Vec<u8>, io::Error> {
+        // lzma-rs decompresses whole blocks in memory before writing them
+        check_xz_unpacked_size(input, decompressed_size)?;
+        let mut output = BoundedWriter {
+            output: Vec::new(),
+            limit: decompressed_size,
+        };
+        ::lzma_rs::xz_decompress(&mut io::BufReader::new(input), &mut output).map_err(|err| {
+            match err {
+                ::lzma_rs::error::Error::IoError(err) => err,
+                err => io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)),
+            }
+        })?;
+        Ok(output.output)
+    }
+}
+
+#[cfg(feature = "lzma")]
+fn invalid_xz(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// checks the LZMA2 chunks of the blocks of an XZ stream, whose headers give their
+/// decompressed size, don't add up to more than `max_size` bytes
+#[cfg(feature = "lzma")]
+fn check_xz_unpacked_size(input: &[u8], max_size: usize) -> Result<(), io::Error> {
+    let byte = |pos: usize| {
+        input
+            .get(pos)
+            .cloned()
+            .ok_or_else(|| invalid_xz("truncated XZ stream"))
+    };
+    let be_u16 = |pos: usize| -> Result<usize, io::Error> {
+        Ok((usize::from(byte(pos)?) << 8) | usize::from(byte(pos + 1)?))
+    };
+    // stream header: magic, flags with the check type, CRC32
+    let check_type = byte(7)? & 0x0F;
+    let check_size = if check_type == 0 {
+        0
+    } else {
+        4 << ((check_type - 1) / 3)
+    };
+    let mut pos = 12;
+    let mut unpacked_size = 0;
+    // a 0 block header size starts the index
+    while byte(pos)? != 0 {
+        let block_start = pos;
+        pos += (usize::from(byte(pos)?) + 1) * 4;
+        loop {
+            let control = byte(pos)?;
+            let (chunk_size, packed_size, header_size) = match control {
+                0x00 => break,
+                0x01 | 0x02 => (be_u16(pos + 1)? + 1, be_u16(pos + 1)? + 1, 3),
+                0x80..=0xFF => (
+                    ((usize::from(control & 0x1F) << 16) | be_u16(pos + 1)?) + 1,
+                    be_u16(pos + 3)? + 1,
+                    if control >= 0xC0 { 6 } else { 5 },
+                ),
+                _ => return Err(invalid_xz("invalid LZMA2 chunk")),
+            };
+            unpacked_size += chunk_size;
+            if unpacked_size > max_size {
+                return Err(invalid_xz("decompressed section larger than its size"));
+            }
+            pos += header_size + packed_size;
+        }
+        pos += 1;
+        // padding to a multiple of 4 bytes, then the check of the block
+        pos += (4 - (pos - block_start) % 4) % 4 + check_size;
+    }
+    Ok(())
+}
+
+/// collects the decompressed data, failing as soon as it exceeds `limit` bytes so a
+/// small stream can't expand without bounds
+#[cfg(feature = "lzma")]
+struct BoundedWriter {
+    output: Vec<u8>,
+    limit: usize,
+}
+
+#[cfg(feature = "lzma")]
+impl io::Write for BoundedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.limit - self.output.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed section larger than its size",
+            ));
+        }
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// compressors known by the decoder when it is created
+#[cfg(feature = "lzma")]
+pub fn builtin_compressors() -> Vec<Box<dyn SecondaryCompressor>> {
+    vec![Box::new(Fgk), Box::new(Lzma)]
+}
+
+#[cfg(not(feature = "lzma"))]
+pub fn builtin_compressors() -> Vec<Box<dyn SecondaryCompressor>> {
+    vec![Box::new(Fgk)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SecondaryCompressor;
+    #[cfg(feature = "lzma")]
+    use crate::test_util::read_file;
+    use crate::varint::VarIntEncode;
+    use crate::{DecoderState, VCDiffDecoder};
+    use std::io;
+    use std::io::Cursor;
+
+    /// stores sections reversed
+    struct Reverse;
+
+    impl SecondaryCompressor for Reverse {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        fn decompress(
+            &self,
+            input: &[u8],
+            _decompressed_size: usize,
+        ) -> Result<Vec<u8>, io::Error> {
+            Ok(input.iter().rev().cloned().collect())
+        }
+    }
+
+    /// a delta with a single window adding `data` through a compressed data section
+    fn delta(compressor_id: u8, data: &[u8], compressed_data: &[u8]) -> Vec<u8> {
+        let mut adds_runs: Vec<u8> = data.len().encode_varint().collect();
+        adds_runs.extend_from_slice(compressed_data);
+        let mut instructions = vec![1u8]; // ADD with explicit size
+        instructions.extend(data.len().encode_varint());
+
+        let mut window = Vec::new();
+        window.extend(data.len().encode_varint());
+        window.push(0x01); // VCD_DATACOMP
+        window.extend(adds_runs.len().encode_varint());
+        window.extend(instructions.len().encode_varint());
+        window.push(0);
+        window.extend_from_slice(&adds_runs);
+        window.extend_from_slice(&instructions);
+
+        let mut delta = vec![0xD6, 0xC3, 0xC4, 0x00, 0x01, compressor_id, 0x00];
+        delta.extend(window.len().encode_varint());
+        delta.extend_from_slice(&window);
+        delta
+    }
+
+    fn decode(
+        delta: &[u8],
+        compressor: Option<Box<dyn SecondaryCompressor>>,
+    ) -> Result<Vec<u8>, io::Error> {
+        let mut target = Cursor::new(Vec::new());
+        {
+            let mut decoder = VCDiffDecoder::new(Cursor::new(Vec::new()), &mut target, 128);
+            if let Some(compressor) = compressor {
+                decoder.add_secondary_compressor(compressor);
+            }
+            assert_eq!(decoder.decode(delta)?, DecoderState::WantMoreInputOrDone);
+        }
+        Ok(target.into_inner())
+    }
+
+    #[test]
+    fn custom_compressor() {
+        let data = b"secondary compression";
+        let reversed: Vec<u8> = data.iter().rev().cloned().collect();
+        let delta = delta(200, data, &reversed);
+        assert!(decode(&delta, None).is_err());
+        assert_eq!(decode(&delta, Some(Box::new(Reverse))).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn fgk() {
+        let data = b"hello world, hello world, hello world, hello world".to_vec();
+        let fgk_delta = delta(super::VCD_FGK_ID, &data, &crate::fgk::compress(&data));
+        assert_eq!(decode(&fgk_delta, None).unwrap(), data);
+    }
+
+    #[cfg(feature = "lzma")]
+    #[test]
+    fn lzma() {
+        let data = b"hello world, hello world, hello world, hello world".to_vec();
+        let mut compressed = Vec::new();
+        ::lzma_rs::xz_compress(&mut &data[..], &mut compressed).unwrap();
+        let lzma_delta = delta(super::VCD_LZMA_ID, &data, &compressed);
+        assert_eq!(decode(&lzma_delta, None).unwrap(), data);
+
+        // the section expands past its decompressed size
+        let zeros = vec![0u8; 1 << 20];
+        let mut compressed = Vec::new();
+        ::lzma_rs::xz_compress(&mut &zeros[..], &mut compressed).unwrap();
+        let bomb = delta(super::VCD_LZMA_ID, &zeros[..1000], &compressed);
+        assert!(decode(&bomb, None).is_err());
+        // rejected before decompressing the block
+        assert!(super::check_xz_unpacked_size(&compressed, 1000).is_err());
+        assert!(super::check_xz_unpacked_size(&compressed, zeros.len()).is_ok());
+        let decompressed = super::Lzma.decompress(&compressed, 1000);
+        assert_eq!(decompressed.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// a delta with LZMA compressed sections, laid out like the ones of `xdelta3 -S lzma`
+    #[cfg(feature = "lzma")]
+    #[test]
+    fn lzma_fixture() {
+        let source = read_file("tst/text-1/src.txt");
+        let target = read_file("tst/text-1/target.txt");
+        let delta = read_file("tst/lzma/text-1.vcdiff");
+        assert_eq!(crate::decode(&source, &delta).unwrap(), target);
+    }
+}
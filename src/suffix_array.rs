@@ -0,0 +1,137 @@
+/// sorts the suffixes of `text` by prefix doubling
+fn suffix_array(text: &[u8]) -> Vec<usize> {
+    let len = text.len();
+    let mut sa: Vec<usize> = (0..len).collect();
+    if len == 0 {
+        return sa;
+    }
+    // rank of the first `k` bytes of each suffix, 0 is the rank past the end of the text
+    let mut rank: Vec<usize> = text.iter().map(|&b| b as usize + 1).collect();
+    let mut next_rank = vec![0; len];
+    let mut k = 1;
+    loop {
+        let key = |rank: &[usize], pos: usize| (rank[pos], rank.get(pos + k).map_or(0, |&r| r));
+        sa.sort_unstable_by_key(|&pos| key(&rank, pos));
+        next_rank[sa[0]] = 1;
+        for idx in 1..len {
+            let new_rank = key(&rank, sa[idx - 1]) < key(&rank, sa[idx]);
+            next_rank[sa[idx]] = next_rank[sa[idx - 1]] + new_rank as usize;
+        }
+        std::mem::swap(&mut rank, &mut next_rank);
+        if rank[sa[len - 1]] == len {
+            break; // all the suffixes are sorted
+        }
+        k *= 2;
+    }
+    sa
+}
+
+/// finds the longest match of each suffix of a text with the suffixes starting before it.
+///
+/// the suffix starting before a position and sharing the longest prefix with it is one
+/// of its two nearest neighbors in the suffix array among the suffixes starting before it.
+pub struct SuffixMatcher {
+    text: Vec<u8>,
+    /// for each position, the positions + 1 (while > 0) of the previous and next suffixes
+    /// in the suffix array that start before it
+    neighbors: Vec<(usize, usize)>,
+}
+
+impl SuffixMatcher {
+    pub fn new(text: Vec<u8>) -> SuffixMatcher {
+        let sa = suffix_array(&text);
+        let mut neighbors = vec![(0, 0); text.len()];
+        // the stacks keep increasing positions, the nearest one lower than each position
+        // is on top
+        let mut stack: Vec<usize> = Vec::new();
+        for &pos in &sa {
+            while stack.last().is_some_and(|&top| top > pos) {
+                stack.pop();
+            }
+            neighbors[pos].0 = stack.last().map_or(0, |&top| top + 1);
+            stack.push(pos);
+        }
+        stack.clear();
+        for &pos in sa.iter().rev() {
+            while stack.last().is_some_and(|&top| top > pos) {
+                stack.pop();
+            }
+            neighbors[pos].1 = stack.last().map_or(0, |&top| top + 1);
+            stack.push(pos);
+        }
+        SuffixMatcher { text, neighbors }
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// returns the start and the length of the longest match of the suffix at `pos`
+    /// starting before `pos`.
+    ///
+    /// the match may overlap the suffix.
+    pub fn longest_match(&self, pos: usize) -> Option<(usize, usize)> {
+        let (previous, next) = self.neighbors[pos];
+        [previous, next]
+            .iter()
+            .filter_map(|&candidate| candidate.checked_sub(1))
+            .map(|candidate| {
+                let len = self.text[candidate..]
+                    .iter()
+                    .zip(&self.text[pos..])
+                    .take_while(|&(a, b)| a == b)
+                    .count();
+                (candidate, len)
+            })
+            .filter(|&(_, len)| len > 0)
+            .max_by_key(|&(candidate, len)| (len, candidate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{suffix_array, SuffixMatcher};
+
+    fn pseudo_random_text(len: usize) -> Vec<u8> {
+        let mut seed = 7u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                b"abc"[(seed >> 16) as usize % 3]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sorted_suffixes() {
+        for text in [&b""[..], b"banana", b"aaaaaaaa", &pseudo_random_text(500)] {
+            let mut expected: Vec<usize> = (0..text.len()).collect();
+            expected.sort_by_key(|&pos| &text[pos..]);
+            assert_eq!(suffix_array(text), expected);
+        }
+    }
+
+    #[test]
+    fn longest_match() {
+        let text = pseudo_random_text(500);
+        let matcher = SuffixMatcher::new(text.clone());
+        for pos in 0..text.len() {
+            let expected = (0..pos)
+                .map(|candidate| {
+                    text[candidate..]
+                        .iter()
+                        .zip(&text[pos..])
+                        .take_while(|&(a, b)| a == b)
+                        .count()
+                })
+                .max()
+                .filter(|&len| len > 0);
+            let found = matcher.longest_match(pos);
+            assert_eq!(found.map(|(_, len)| len), expected);
+            if let Some((candidate, len)) = found {
+                assert!(candidate < pos);
+                assert_eq!(text[candidate..candidate + len], text[pos..pos + len]);
+            }
+        }
+    }
+}
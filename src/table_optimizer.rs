@@ -0,0 +1,171 @@
+use crate::address_cache::AddressCache;
+use crate::code_table::{CodeTable, Instruction, InstructionType};
+use crate::error::Error;
+use crate::instructions::{windows, CopyFrom, Op};
+use crate::varint::VarIntEncode;
+use std::cmp;
+use std::collections::HashMap;
+
+/// address cache sizes of the derived code tables, the default ones
+static NEAR_CACHE_SIZE: u8 = 4;
+static SAME_CACHE_SIZE: u8 = 3;
+
+/// instruction as found in a code table entry: type, size (0 when it doesn't fit
+/// in the table) and address mode
+type InstructionKey = (InstructionType, u8, u8);
+
+/// counts of the instructions of a corpus of deltas, to derive a code table fitting them.
+///
+/// the COPY modes are recomputed with the default address caches, whatever the
+/// cache sizes of the deltas.
+#[derive(Debug, Clone, Default)]
+pub struct InstructionStats {
+    singles: HashMap<InstructionKey, u64>,
+    /// instructions following each other in a window
+    doubles: HashMap<(InstructionKey, InstructionKey), u64>,
+}
+
+impl InstructionStats {
+    pub fn new() -> InstructionStats {
+        InstructionStats::default()
+    }
+
+    /// counts the instructions of `delta`
+    pub fn add_delta(&mut self, delta: &[u8]) -> Result<(), Error> {
+        for window in windows(delta) {
+            let window = window?;
+            let source_length = window.header().source_segment.map_or(0, |s| s.1);
+            let mut address_cache =
+                AddressCache::new(NEAR_CACHE_SIZE as usize, SAME_CACHE_SIZE as usize);
+            let mut here = source_length;
+            let mut previous = None;
+            for op in window.instructions() {
+                let (typ, size, mode) = match op? {
+                    Op::Add(bytes) => (InstructionType::Add, bytes.len(), 0),
+                    Op::Run(_, len) => (InstructionType::Run, len, 0),
+                    Op::Copy { addr, len, from } => {
+                        let addr = match from {
+                            CopyFrom::Source => addr,
+                            CopyFrom::Target => source_length + addr,
+                        };
+                        (
+                            InstructionType::Copy,
+                            len,
+                            address_cache.encode(addr, here).1,
+                        )
+                    }
+                };
+                here += size as u64;
+                let size = if size <= 255 { size as u8 } else { 0 };
+                let key = (typ, size, mode);
+                *self.singles.entry(key).or_insert(0) += 1;
+                if let Some(previous) = previous {
+                    *self.doubles.entry((previous, key)).or_insert(0) += 1;
+                }
+                previous = Some(key);
+            }
+        }
+        Ok(())
+    }
+
+    /// derives the code table saving the most bytes on the counted instructions.
+    ///
+    /// the table keeps an explicit size opcode for ADD, RUN and COPY in every mode, the
+    /// other opcodes go to the instructions and pairs of instructions whose opcode would
+    /// save the most size and opcode bytes. The savings are estimated independently for
+    /// each opcode.
+    pub fn code_table(&self) -> CodeTable {
+        let mut builder = CodeTable::builder()
+            .near_cache_size(NEAR_CACHE_SIZE)
+            .same_cache_size(SAME_CACHE_SIZE)
+            .single(Instruction::run(0))
+            .single(Instruction::add(0));
+        let modes = 2 + NEAR_CACHE_SIZE + SAME_CACHE_SIZE;
+        for mode in 0..modes {
+            builder = builder.single(Instruction::copy(0, mode));
+        }
+
+        // bytes saved by not writing the size after the opcode
+        let size_savings = |&(_, size, _): &InstructionKey| -> u64 {
+            if size > 0 {
+                u32::from(size).encode_varint().count() as u64
+            } else {
+                0
+            }
+        };
+        let mut candidates: Vec<(u64, InstructionKey, Option<InstructionKey>)> = Vec::new();
+        for (key, &count) in &self.singles {
+            if key.1 > 0 {
+                candidates.push((count * size_savings(key), *key, None));
+            }
+        }
+        for (&(first, second), &count) in &self.doubles {
+            // the opcode of the second instruction is saved too
+            let savings = 1 + size_savings(&first) + size_savings(&second);
+            candidates.push((count * savings, first, Some(second)));
+        }
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| (a.1, a.2).cmp(&(b.1, b.2))));
+
+        let available = 256 - 2 - modes as usize;
+        for &(_, first, second) in &candidates[..cmp::min(available, candidates.len())] {
+            let inst = |(typ, size, mode): InstructionKey| Instruction { typ, size, mode };
+            builder = match second {
+                None => builder.single(inst(first)),
+                Some(second) => builder.double(inst(first), inst(second)),
+            };
+        }
+        builder.build().expect("valid derived code table")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InstructionStats;
+    use crate::{decode, windows, VCDiffEncoder};
+    use std::io::Cursor;
+
+    /// records with a small edit in each of them
+    fn records(edit: usize) -> (Vec<u8>, Vec<u8>) {
+        let mut source = Vec::new();
+        let mut target = Vec::new();
+        for idx in 0..500 {
+            source.extend(format!("record {:05}: value={:04} status=ok\n", idx, idx).bytes());
+            let value = (idx * 7 + edit) % 10000;
+            target.extend(format!("record {:05}: value={:04} status=ok\n", idx, value).bytes());
+        }
+        (source, target)
+    }
+
+    /// size of the windows of `delta`, without its header
+    fn windows_size(delta: &[u8]) -> u64 {
+        windows(delta)
+            .map(|window| u64::from(window.unwrap().header().delta_encoding_size))
+            .sum()
+    }
+
+    #[test]
+    fn code_table() {
+        let encode = |source: &[u8], target: &[u8], stats: Option<&InstructionStats>| {
+            let mut delta = Vec::new();
+            let mut encoder =
+                VCDiffEncoder::new(Cursor::new(source), Cursor::new(target), 4).unwrap();
+            if let Some(stats) = stats {
+                encoder.set_code_table(stats.code_table());
+            }
+            encoder.encode(&mut delta).unwrap();
+            delta
+        };
+
+        let mut stats = InstructionStats::new();
+        for edit in 0..4 {
+            let (source, target) = records(edit);
+            stats.add_delta(&encode(&source, &target, None)).unwrap();
+        }
+
+        let (source, target) = records(9);
+        let default_delta = encode(&source, &target, None);
+        let delta = encode(&source, &target, Some(&stats));
+        assert_eq!(decode(&source, &delta).unwrap(), target);
+        assert!(windows_size(&delta) < windows_size(&default_delta));
+    }
+}
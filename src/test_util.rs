@@ -0,0 +1,9 @@
+use std::fs::File;
+use std::io::Read;
+
+/// reads a test fixture
+pub fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
@@ -1,21 +1,17 @@
-use nom;
 use nom::{IResult, Needed};
 use std::mem;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub struct VarIntIncomplete<I> {
-    value: I,
-    shift: u32,
-}
-
 pub trait VarIntDecode<I> {
     fn decode_varint(i: &[u8]) -> IResult<&[u8], I>;
 }
 
-trait VarIntEncode<I> {
+// only the encoder and the tests write varints
+#[cfg_attr(not(feature = "encoder"), allow(dead_code))]
+pub trait VarIntEncode<I> {
     fn encode_varint(&self) -> VarIntEncoder<I>;
 }
 
+#[cfg_attr(not(feature = "encoder"), allow(dead_code))]
 #[derive(Debug, PartialEq)]
 pub struct VarIntEncoder<I> {
     value: I,
@@ -107,9 +103,8 @@ impl_var_int_decode!(usize);
 
 #[cfg(test)]
 mod tests {
-    use nom;
+    use crate::varint::{VarIntDecode, VarIntEncode};
     use nom::IResult;
-    use varint::{VarIntDecode, VarIntEncode};
 
     macro_rules! impl_tests {
         ($T:ty, $overflow_name:ident) => {
@@ -1,13 +1,26 @@
-use varint::VarIntDecode;
+use crate::varint::VarIntDecode;
+#[cfg(feature = "encoder")]
+use crate::varint::VarIntEncode;
 
-use code_table::CodeTable;
 use nom::{be_u32, IResult};
 
+/// custom code table of a header (VCD_CODETABLE), before its decoding
+pub struct CodeTableData {
+    pub near_cache_size: u8,
+    pub same_cache_size: u8,
+    /// delta of the byte layout of the table against the one of the default table
+    pub delta: Vec<u8>,
+}
+
 pub struct VCDiffHeader {
-    pub custom_code_table: Option<CodeTable>,
+    /// version byte of the magic, `VCD_VERSION_RFC` or `VCD_VERSION_SDCH`
+    pub version: u8,
+    pub secondary_compressor_id: Option<u8>,
+    pub custom_code_table: Option<CodeTableData>,
+    pub app_header: Option<Vec<u8>>,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub struct WindowHeader {
     /**
       This byte is a set of bits, as shown:
@@ -64,24 +77,101 @@ pub struct WindowHeader {
       This is the length (in bytes) of the delta instructions and
       accompanying sizes.
     */
-    pub intructions_size: u32,
+    pub instructions_size: u32,
 
     /**
       This is the length (in bytes) of the section storing the
       addresses of the COPY instructions.
     */
     pub copy_addresses_size: u32,
+
+    /**
+      Adler-32 checksum of the target window, present when the
+      VCD_ADLER32 bit of the Win_Indicator is set (xdelta3 and
+      open-vcdiff extension, see `ChecksumDialect`).
+    */
+    pub adler32: Option<u32>,
+}
+
+#[cfg(feature = "encoder")]
+impl WindowHeader {
+    /// Size of everything following the delta_encoding_size field
+    pub fn compute_delta_encoding_size(&self) -> u32 {
+        let varint_len = |v: u32| v.encode_varint().count() as u32;
+        varint_len(self.target_window_size)
+            + 1
+            + varint_len(self.adds_runs_size)
+            + varint_len(self.instructions_size)
+            + varint_len(self.copy_addresses_size)
+            + self.adds_runs_size
+            + self.instructions_size
+            + self.copy_addresses_size
+            + self.adler32.map_or(0, |_| 4)
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.win_indicator);
+        if let Some((pos, sz)) = self.source_segment {
+            out.extend(sz.encode_varint());
+            out.extend(pos.encode_varint());
+        }
+        out.extend(self.delta_encoding_size.encode_varint());
+        out.extend(self.target_window_size.encode_varint());
+        out.push(self.delta_indicator);
+        out.extend(self.adds_runs_size.encode_varint());
+        out.extend(self.instructions_size.encode_varint());
+        out.extend(self.copy_addresses_size.encode_varint());
+        if let Some(adler32) = self.adler32 {
+            out.extend_from_slice(&[
+                (adler32 >> 24) as u8,
+                (adler32 >> 16) as u8,
+                (adler32 >> 8) as u8,
+                adler32 as u8,
+            ]);
+        }
+    }
 }
 
-// static VCD_DECOMPRESS: u8 = 0x01;
-static VCD_CODETABLE: u8 = 0x02;
-static VCD_APPHEADER: u8 = 0x04;
+pub static VCDIFF_MAGIC: [u8; 4] = [0xD6, 0xC3, 0xC4, 0x00];
+
+/// version byte of RFC 3284 deltas
+pub static VCD_VERSION_RFC: u8 = 0x00;
+/// version byte of the open-vcdiff format extensions ('S'), used by SDCH
+pub static VCD_VERSION_SDCH: u8 = 0x53;
+
+static VCD_DECOMPRESS: u8 = 0x01;
+pub static VCD_CODETABLE: u8 = 0x02;
+pub static VCD_APPHEADER: u8 = 0x04;
 
 pub static VCD_SOURCE: u8 = 0x01;
 pub static VCD_TARGET: u8 = 0x02;
 pub static VCD_ADLER32: u8 = 0x04;
 
-fn is_flag_set(value: u8, flag: u8) -> bool {
+pub static VCD_DATACOMP: u8 = 0x01;
+pub static VCD_INSTCOMP: u8 = 0x02;
+pub static VCD_ADDRCOMP: u8 = 0x04;
+
+/// encoding of the window checksum announced by the VCD_ADLER32 bit of the Win_Indicator
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChecksumDialect {
+    /// 4 bytes big-endian, as written by xdelta3
+    Xdelta3,
+    /// variable length integer, as written by open-vcdiff
+    OpenVcdiff,
+}
+
+impl ChecksumDialect {
+    /// dialect used by the deltas with the `version` byte
+    pub fn from_version(version: u8) -> ChecksumDialect {
+        if version == VCD_VERSION_SDCH {
+            ChecksumDialect::OpenVcdiff
+        } else {
+            ChecksumDialect::Xdelta3
+        }
+    }
+}
+
+pub fn is_flag_set(value: u8, flag: u8) -> bool {
     value & flag == flag
 }
 
@@ -94,21 +184,45 @@ fn u64_decode_varint(i: &[u8]) -> IResult<&[u8], u64> {
 }
 
 named!(get_byte<u8>, map!(take!(1), |bs| bs[0]));
-named!(header_magic, tag!([0xD6, 0xC3, 0xC4, 0x00]));
-named!(app_header<&[u8], ()>, do_parse!(
+named!(
+    header_magic<u8>,
+    do_parse!(
+         tag!([0xD6, 0xC3, 0xC4])
+      >> version: verify!(get_byte, |v: u8| v == VCD_VERSION_RFC || v == VCD_VERSION_SDCH)
+      >>
+      (version)
+    )
+);
+named!(app_header<&[u8], Vec<u8>>, do_parse!(
      sz: u32_decode_varint
-  >> take!(sz)
+  >> data: take!(sz)
   >>
-  ()
+  (data.to_vec())
+));
+named!(code_table_data<&[u8], CodeTableData>, do_parse!(
+     sz: verify!(u32_decode_varint, |sz: u32| sz >= 2)
+  >> near_cache_size: get_byte
+  >> same_cache_size: get_byte
+  >> delta: take!(sz - 2)
+  >>
+  (CodeTableData {
+    near_cache_size,
+    same_cache_size,
+    delta: delta.to_vec(),
+  })
 ));
 named!(pub header<VCDiffHeader>, do_parse!(
-     header_magic
+     version: header_magic
   >> hdr_indicator: get_byte
-  >> custom_code_table: cond!(is_flag_set(hdr_indicator, VCD_CODETABLE), call!(CodeTable::decode))
-  >> cond!(is_flag_set(hdr_indicator, VCD_APPHEADER), call!(app_header))
+  >> secondary_compressor_id: cond!(is_flag_set(hdr_indicator, VCD_DECOMPRESS), call!(get_byte))
+  >> custom_code_table: cond!(is_flag_set(hdr_indicator, VCD_CODETABLE), call!(code_table_data))
+  >> app_header: cond!(is_flag_set(hdr_indicator, VCD_APPHEADER), call!(app_header))
   >>
   (VCDiffHeader {
-    custom_code_table
+    version,
+    secondary_compressor_id,
+    custom_code_table,
+    app_header
   })
 ));
 
@@ -118,28 +232,38 @@ named!(source_segment_size_pos<&[u8], (u64, u64)>, do_parse!(
   >>
   (pos, sz)
 ));
-named!(pub window_header<&[u8], WindowHeader>, do_parse!(
-     win_indicator: get_byte
-  >> source_segment: cond!(
-    (win_indicator & (VCD_SOURCE | VCD_TARGET)) > 0,
-    call!(source_segment_size_pos)
-  )
-  >> delta_encoding_size: u32_decode_varint
-  >> target_window_size: u32_decode_varint
-  >> delta_indicator: get_byte
-  >> adds_runs_size: u32_decode_varint
-  >> intructions_size: u32_decode_varint
-  >> copy_addresses_size: u32_decode_varint
-  >> cond!((win_indicator & VCD_ADLER32) > 0, call!(be_u32))
-  >>
-  (WindowHeader {
-    win_indicator,
-    source_segment,
-    delta_encoding_size,
-    target_window_size,
-    delta_indicator,
-    adds_runs_size,
-    intructions_size,
-    copy_addresses_size,
-  })
-));
+fn checksum(i: &[u8], dialect: ChecksumDialect) -> IResult<&[u8], u32> {
+    match dialect {
+        ChecksumDialect::Xdelta3 => be_u32(i),
+        ChecksumDialect::OpenVcdiff => u32_decode_varint(i),
+    }
+}
+
+pub fn window_header(i: &[u8], checksum_dialect: ChecksumDialect) -> IResult<&[u8], WindowHeader> {
+    do_parse!(i,
+         win_indicator: get_byte
+      >> source_segment: cond!(
+        (win_indicator & (VCD_SOURCE | VCD_TARGET)) > 0,
+        call!(source_segment_size_pos)
+      )
+      >> delta_encoding_size: u32_decode_varint
+      >> target_window_size: u32_decode_varint
+      >> delta_indicator: get_byte
+      >> adds_runs_size: u32_decode_varint
+      >> instructions_size: u32_decode_varint
+      >> copy_addresses_size: u32_decode_varint
+      >> adler32: cond!((win_indicator & VCD_ADLER32) > 0, call!(checksum, checksum_dialect))
+      >>
+      (WindowHeader {
+        win_indicator,
+        source_segment,
+        delta_encoding_size,
+        target_window_size,
+        delta_indicator,
+        adds_runs_size,
+        instructions_size,
+        copy_addresses_size,
+        adler32,
+      })
+    )
+}
@@ -0,0 +1,131 @@
+use crate::address_cache::AddressCache;
+use crate::code_table::{InstructionType, OpcodeMap};
+use crate::varint::VarIntEncode;
+
+/// shortest run of identical bytes worth a RUN instruction inside added data
+pub(crate) static MIN_RUN_LENGTH: usize = 8;
+
+#[derive(Debug, Copy, Clone)]
+struct PendingInstruction {
+    typ: InstructionType,
+    size: usize,
+    mode: u8,
+}
+
+/// the three sections of a delta window, ready to be written after its header
+pub struct WindowSections {
+    pub adds_runs: Vec<u8>,
+    pub instructions: Vec<u8>,
+    pub copy_addresses: Vec<u8>,
+}
+
+/// turns ADD/RUN/COPY instructions into the sections of a delta window.
+///
+/// the last instruction is kept pending so it can be merged with the next one
+/// when the code table has a matching double instruction opcode.
+pub struct WindowWriter<'a> {
+    opcodes: &'a OpcodeMap,
+    address_cache: AddressCache,
+    here: u64,
+    pending: Option<PendingInstruction>,
+    sections: WindowSections,
+}
+
+impl<'a> WindowWriter<'a> {
+    pub fn new(opcodes: &'a OpcodeMap, source_length: u64) -> WindowWriter<'a> {
+        WindowWriter {
+            opcodes,
+            address_cache: opcodes.address_cache(),
+            here: source_length,
+            pending: None,
+            sections: WindowSections {
+                adds_runs: Vec::new(),
+                instructions: Vec::new(),
+                copy_addresses: Vec::new(),
+            },
+        }
+    }
+
+    /// adds unmatched bytes, long runs of identical bytes are emitted as RUN
+    pub fn add(&mut self, bytes: &[u8]) {
+        let mut start = 0;
+        let mut idx = 0;
+        while idx < bytes.len() {
+            let byte = bytes[idx];
+            let run_end = idx + bytes[idx..].iter().take_while(|&&b| b == byte).count();
+            if run_end - idx >= MIN_RUN_LENGTH {
+                self.add_literal(&bytes[start..idx]);
+                self.run(byte, run_end - idx);
+                start = run_end;
+            }
+            idx = run_end;
+        }
+        self.add_literal(&bytes[start..]);
+    }
+
+    fn add_literal(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.sections.adds_runs.extend_from_slice(bytes);
+        self.push(InstructionType::Add, bytes.len(), 0);
+    }
+
+    pub fn run(&mut self, byte: u8, size: usize) {
+        self.sections.adds_runs.push(byte);
+        self.push(InstructionType::Run, size, 0);
+    }
+
+    /// copies `size` bytes from `addr` in the source segment + target window address space
+    pub fn copy(&mut self, addr: u64, size: usize) {
+        let (value, mode) = self.address_cache.encode(addr, self.here);
+        if self.address_cache.is_same_mode(mode) {
+            self.sections.copy_addresses.push(value as u8);
+        } else {
+            self.sections.copy_addresses.extend(value.encode_varint());
+        }
+        self.push(InstructionType::Copy, size, mode);
+    }
+
+    fn push(&mut self, typ: InstructionType, size: usize, mode: u8) {
+        self.here += size as u64;
+        let inst = PendingInstruction { typ, size, mode };
+        if let Some(pending) = self.pending.take() {
+            let double = self.opcodes.double(
+                (pending.typ, pending.size, pending.mode),
+                (inst.typ, inst.size, inst.mode),
+            );
+            if let Some((opcode, explicit_first, explicit_second)) = double {
+                let instructions = &mut self.sections.instructions;
+                instructions.push(opcode);
+                if explicit_first {
+                    instructions.extend(pending.size.encode_varint());
+                }
+                if explicit_second {
+                    instructions.extend(inst.size.encode_varint());
+                }
+                return;
+            }
+            self.write_single(pending);
+        }
+        self.pending = Some(inst);
+    }
+
+    fn write_single(&mut self, inst: PendingInstruction) {
+        let (opcode, explicit) = self
+            .opcodes
+            .single(inst.typ, inst.size, inst.mode)
+            .expect("code table must have an explicit size opcode for every instruction");
+        self.sections.instructions.push(opcode);
+        if explicit {
+            self.sections.instructions.extend(inst.size.encode_varint());
+        }
+    }
+
+    pub fn finish(mut self) -> WindowSections {
+        if let Some(pending) = self.pending.take() {
+            self.write_single(pending);
+        }
+        self.sections
+    }
+}
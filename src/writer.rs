@@ -0,0 +1,78 @@
+use crate::decoder::{DecoderState, VCDiffDecoder};
+use crate::error::Error;
+use std::io;
+use std::io::{Read, Seek, Write};
+
+/// Decoder accepting the delta through `io::Write`, e.g. with `io::copy`.
+///
+/// `finish` must be called once the whole delta is written, to detect truncated deltas.
+pub struct VCDiffWriter<ORIGINAL: Read + Seek, TARGET: Write> {
+    decoder: VCDiffDecoder<ORIGINAL, TARGET>,
+    state: DecoderState,
+}
+
+impl<ORIGINAL: Read + Seek, TARGET: Write> VCDiffWriter<ORIGINAL, TARGET> {
+    pub fn new(decoder: VCDiffDecoder<ORIGINAL, TARGET>) -> VCDiffWriter<ORIGINAL, TARGET> {
+        VCDiffWriter {
+            decoder,
+            state: DecoderState::WantMoreInput,
+        }
+    }
+
+    pub fn get_ref(&self) -> &VCDiffDecoder<ORIGINAL, TARGET> {
+        &self.decoder
+    }
+
+    pub fn get_mut(&mut self) -> &mut VCDiffDecoder<ORIGINAL, TARGET> {
+        &mut self.decoder
+    }
+
+    /// checks the delta is complete and flushes the target
+    pub fn finish(mut self) -> Result<VCDiffDecoder<ORIGINAL, TARGET>, Error> {
+        if self.state != DecoderState::WantMoreInputOrDone {
+            Err(Error::UnexpectedEof)?;
+        }
+        self.decoder.get_mut().1.flush()?;
+        Ok(self.decoder)
+    }
+}
+
+impl<ORIGINAL: Read + Seek, TARGET: Write> Write for VCDiffWriter<ORIGINAL, TARGET> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state = self.decoder.decode(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.decoder.get_mut().1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::read_file;
+    use crate::{Error, VCDiffDecoder, VCDiffWriter};
+    use std::fs::File;
+    use std::io;
+    use std::io::Cursor;
+
+    fn decode(patch: &[u8]) -> Result<Vec<u8>, Error> {
+        let src = File::open("tst/text-1/src.txt").unwrap();
+        let decoder = VCDiffDecoder::new(src, Cursor::new(Vec::new()), 0);
+        let mut writer = VCDiffWriter::new(decoder);
+        io::copy(&mut &patch[..], &mut writer)?;
+        let (_, target) = writer.finish()?.into_inner();
+        Ok(target.into_inner())
+    }
+
+    #[test]
+    fn text_1() {
+        let patch = read_file("tst/text-1/l.patch");
+        let target = read_file("tst/text-1/target.txt");
+        assert_eq!(decode(&patch).unwrap(), target);
+        match decode(&patch[..patch.len() - 10]) {
+            Err(Error::UnexpectedEof) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
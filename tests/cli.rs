@@ -0,0 +1,78 @@
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::process;
+use std::process::{Command, Output};
+use vcdiff_rs::{CodeTable, Instruction, VCDiffEncoder};
+
+fn vcdiff(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_vcdiff"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "vcdiff {:?}: {:?}", args, output);
+    output
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("vcdiff-cli-{}-{}", process::id(), name))
+}
+
+#[test]
+fn encode_decode_inspect() {
+    let patch = temp_path("text-1.vcdiff");
+    let decoded = temp_path("text-1.txt");
+    let patch = patch.to_str().unwrap();
+    let decoded = decoded.to_str().unwrap();
+
+    vcdiff(&[
+        "encode",
+        "tst/text-1/src.txt",
+        "tst/text-1/target.txt",
+        patch,
+    ]);
+    vcdiff(&["decode", "tst/text-1/src.txt", patch, decoded]);
+    assert_eq!(
+        fs::read(decoded).unwrap(),
+        fs::read("tst/text-1/target.txt").unwrap()
+    );
+    let info = String::from_utf8(vcdiff(&["info", patch]).stdout).unwrap();
+    assert!(info.contains("code table:               default"));
+    let dump = String::from_utf8(vcdiff(&["dump", patch]).stdout).unwrap();
+    assert!(dump.contains("CPY"));
+
+    fs::remove_file(patch).unwrap();
+    fs::remove_file(decoded).unwrap();
+}
+
+#[test]
+fn info_code_table() {
+    let mut builder = CodeTable::builder()
+        .near_cache_size(2)
+        .same_cache_size(1)
+        .single(Instruction::run(0))
+        .single(Instruction::add(0));
+    for mode in 0..5 {
+        builder = builder.single(Instruction::copy(0, mode));
+    }
+    let code_table = builder.single(Instruction::add(3)).build().unwrap();
+    let source = fs::read("tst/text-1/src.txt").unwrap();
+    let target = fs::read("tst/text-1/target.txt").unwrap();
+    let mut encoder = VCDiffEncoder::new(Cursor::new(&source), Cursor::new(&target), 16).unwrap();
+    encoder.set_code_table(code_table);
+    let mut delta = Vec::new();
+    encoder.encode(&mut delta).unwrap();
+
+    let patch = temp_path("code-table.vcdiff");
+    fs::write(&patch, &delta).unwrap();
+    let info = String::from_utf8(vcdiff(&["info", patch.to_str().unwrap()]).stdout).unwrap();
+    fs::remove_file(&patch).unwrap();
+    assert!(info.contains("code table:               custom"));
+    assert!(info.contains("near cache size:          2"));
+    assert!(info.contains("same cache size:          1"));
+    // the default table has RUN 0 then ADD 0 to ADD 17
+    assert!(info.contains("  opcode 2                 CPY 0 mode 0"));
+    assert!(info.contains("  opcode 7                 ADD 3"));
+    assert!(info.contains("  opcode 8                 NOOP"));
+}